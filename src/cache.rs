@@ -0,0 +1,23 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+};
+
+use crate::{error::Result, table::BinaryTable};
+
+impl BinaryTable {
+    pub fn save<P>(&self, path: P) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        self.write_to(BufWriter::new(File::create(path)?))
+    }
+
+    pub fn load<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::read_from(BufReader::new(File::open(path)?))
+    }
+}
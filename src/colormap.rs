@@ -0,0 +1,150 @@
+use std::str::FromStr;
+
+use crate::error::BvError;
+
+const VIRIDIS: [[u8; 3]; 9] = [
+    [68, 1, 84],
+    [72, 40, 120],
+    [62, 73, 137],
+    [49, 104, 142],
+    [38, 130, 142],
+    [31, 158, 137],
+    [53, 183, 121],
+    [110, 206, 88],
+    [253, 231, 37],
+];
+
+const INFERNO: [[u8; 3]; 9] = [
+    [0, 0, 4],
+    [31, 12, 72],
+    [85, 15, 109],
+    [136, 34, 106],
+    [186, 54, 85],
+    [227, 89, 51],
+    [249, 140, 10],
+    [249, 201, 50],
+    [252, 255, 164],
+];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Colormap {
+    #[default]
+    Green,
+    Grayscale,
+    Viridis,
+    Inferno,
+}
+
+impl Colormap {
+    /// Maps a normalized value in `[0, 1]` to an RGB color with components in `[0, 1]`.
+    pub fn color(self, t: f32) -> [f32; 3] {
+        let t = if t.is_nan() { 0.0 } else { t.clamp(0.0, 1.0) };
+        match self {
+            Self::Green => [0.0, t, 0.0],
+            Self::Grayscale => [t, t, t],
+            Self::Viridis => interpolate(&VIRIDIS, t),
+            Self::Inferno => interpolate(&INFERNO, t),
+        }
+    }
+}
+
+impl FromStr for Colormap {
+    type Err = BvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "green" => Ok(Self::Green),
+            "grayscale" | "greyscale" => Ok(Self::Grayscale),
+            "viridis" => Ok(Self::Viridis),
+            "inferno" => Ok(Self::Inferno),
+            _ => Err(BvError::InvalidConfig(format!("Unknown colormap '{s}'"))),
+        }
+    }
+}
+
+/// Colors every value of an exported table, producing row-major RGBA pixels as the viewer
+/// draws them.
+pub fn render_rgba(export: &[f32], colormap: Colormap) -> Vec<u8> {
+    render_with(export, |t| colormap.color(t))
+}
+
+/// Colors a signed difference from `BinaryTable::diff` with `diverging`.
+pub fn render_diff_rgba(diff: &[f32]) -> Vec<u8> {
+    render_with(diff, diverging)
+}
+
+fn render_with<F>(values: &[f32], color: F) -> Vec<u8>
+where
+    F: Fn(f32) -> [f32; 3],
+{
+    let mut pixels = Vec::with_capacity(values.len() * 4);
+    for &t in values {
+        let [r, g, b] = color(t);
+        pixels.extend([r, g, b].map(|c| (c * 255.0) as u8));
+        pixels.push(255);
+    }
+    pixels
+}
+
+/// Maps a signed difference in `[-1, 1]` to red for positive and green for negative values.
+pub fn diverging(t: f32) -> [f32; 3] {
+    let t = if t.is_nan() { 0.0 } else { t.clamp(-1.0, 1.0) };
+    if t >= 0.0 {
+        [t, 0.0, 0.0]
+    } else {
+        [0.0, -t, 0.0]
+    }
+}
+
+fn interpolate(stops: &[[u8; 3]], t: f32) -> [f32; 3] {
+    let position = t * (stops.len() - 1) as f32;
+    let index = (position as usize).min(stops.len() - 2);
+    let fraction = position - index as f32;
+    let [a, b] = [stops[index], stops[index + 1]];
+    let mut color = [0.0; 3];
+    for (i, channel) in color.iter_mut().enumerate() {
+        let (a, b) = (a[i] as f32 / 255.0, b[i] as f32 / 255.0);
+        *channel = a + (b - a) * fraction;
+    }
+    color
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::BinaryTable;
+
+    #[test]
+    fn colormaps_span_their_palettes() {
+        assert_eq!(Colormap::default(), Colormap::Green);
+        assert_eq!(Colormap::Green.color(0.5), [0.0, 0.5, 0.0]);
+        assert_eq!(Colormap::Grayscale.color(2.0), [1.0; 3]);
+        assert_eq!(
+            Colormap::Inferno.color(f32::NAN),
+            Colormap::Inferno.color(0.0)
+        );
+        let top = VIRIDIS[VIRIDIS.len() - 1].map(|c| c as f32 / 255.0);
+        assert_eq!(Colormap::Viridis.color(1.0), top);
+        assert_eq!(
+            "greyscale".parse::<Colormap>().unwrap(),
+            Colormap::Grayscale
+        );
+        assert!("rainbow".parse::<Colormap>().is_err());
+    }
+
+    /// FNV-1a, which unlike `DefaultHasher` is guaranteed to stay the same.
+    fn fnv1a(bytes: &[u8]) -> u64 {
+        bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+    }
+
+    #[test]
+    fn rendered_pixels_match_the_snapshot() {
+        let mut table = BinaryTable::new();
+        table.parse(b"snapshot of a small known table");
+        let pixels = render_rgba(&table.export(), Colormap::Viridis);
+        assert_eq!(pixels.len(), 256 * 256 * 4);
+        assert_eq!(fnv1a(&pixels), 393132080533742373);
+    }
+}
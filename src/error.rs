@@ -0,0 +1,55 @@
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+pub type Result<T, E = BvError> = std::result::Result<T, E>;
+
+#[derive(Debug, Error)]
+pub enum BvError {
+    #[error("Dataset too small")]
+    DatasetTooSmall,
+    #[error("The model is not trained well enough ({accuracy:.2}% test accuracy)")]
+    ModelNotConverged { accuracy: f32 },
+    #[error("Input has fewer than two bytes, so there are no byte pairs to classify")]
+    InputTooShort,
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+    #[error("Model was saved by version {version} with architecture {found}, expected {expected}")]
+    ModelVersionMismatch {
+        version: String,
+        found: String,
+        expected: String,
+    },
+    #[error("Invalid model: {0}")]
+    InvalidModel(String),
+    #[error("Invalid model file {}: {source}", path.display())]
+    InvalidModelFile {
+        path: PathBuf,
+        source: ModelFileError,
+    },
+    #[error("Invalid table file: {0}")]
+    InvalidTable(String),
+    #[error("Expected a tensor of shape {expected}, got {got:?}")]
+    ShapeMismatch { expected: String, got: Vec<usize> },
+    #[error("Unsupported device: {0}")]
+    UnsupportedDevice(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[cfg(feature = "ml")]
+    #[error(transparent)]
+    Candle(#[from] candle::Error),
+    #[cfg(feature = "ml")]
+    #[error(transparent)]
+    SafeTensors(#[from] safetensors::SafeTensorError),
+}
+
+/// Why a path could not be loaded as a model, see `BvError::InvalidModelFile`.
+#[derive(Debug, Error)]
+pub enum ModelFileError {
+    #[error("file does not exist")]
+    NotFound,
+    #[error("not a file")]
+    NotAFile,
+    #[error("could not parse safetensors - {0}")]
+    Parse(String),
+}
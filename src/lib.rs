@@ -1,2 +1,107 @@
+#[cfg(feature = "ml")]
+use std::path::Path;
+
+#[cfg(feature = "ml")]
+use candle::Device;
+use colormap::{render_rgba, Colormap};
+#[cfg(feature = "ml")]
+use error::{BvError, Result};
+#[cfg(feature = "ml")]
+use ml::{argmax, FileType, Network};
+use table::BinaryTable;
+
+pub mod cache;
+pub mod colormap;
+pub mod error;
+#[cfg(feature = "ml")]
 pub mod ml;
+#[cfg(feature = "python")]
+mod python;
 pub mod table;
+
+/// Parses `bytes` and renders the table as 256×256 RGBA pixels, row by row.
+pub fn visualize_bytes(bytes: &[u8]) -> Vec<u8> {
+    let mut table = BinaryTable::new();
+    table.parse(bytes);
+    render_rgba(&table.export(), Colormap::default())
+}
+
+/// Loads the model at `model_path` and returns the most likely file type of `bytes` along with
+/// its probability.
+#[cfg(feature = "ml")]
+pub fn classify<P>(model_path: P, bytes: &[u8], dev: &Device) -> Result<(FileType, f32)>
+where
+    P: AsRef<Path>,
+{
+    let network = Network::load(model_path, dev)?;
+    let mut table = BinaryTable::new();
+    table.parse(network.sample(bytes));
+    let probabilities = network.predict_probabilities(&table, dev)?;
+    let (output, confidence) = argmax(&probabilities);
+    let file_type = network
+        .file_type(output)
+        .ok_or_else(|| BvError::InvalidModel(format!("no class for output {output}")))?;
+    Ok((file_type, confidence))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn visualized_bytes_are_rgba_pixels() {
+        assert_eq!(visualize_bytes(b"some bytes").len(), 256 * 256 * 4);
+        assert_eq!(visualize_bytes(&[]).len(), 256 * 256 * 4);
+    }
+
+    #[cfg(feature = "ml")]
+    #[test]
+    fn classify_with_a_freshly_trained_model() {
+        use ml::{train, Dataset, DatasetConfig, NetworkConfig, Optimizer, TrainConfig};
+
+        let dir = std::env::temp_dir().join(format!("bv-classify-{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let text = b"plain lowercase words separated by spaces and newlines\n".repeat(8);
+        for i in 0..6u8 {
+            let binary: Vec<u8> = (0..512u32)
+                .map(|j| (j * 97 + i as u32 * 31) as u8)
+                .collect();
+            std::fs::write(
+                dir.join(format!("{i}.txt")),
+                [&[b'a' + i], &text[..]].concat(),
+            )
+            .unwrap();
+            std::fs::write(dir.join(format!("{i}.wav")), binary).unwrap();
+        }
+        let config = DatasetConfig {
+            seed: Some(1),
+            ..Default::default()
+        };
+        let dataset = Dataset::collect(&dir, &config, &Device::Cpu);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let config = TrainConfig {
+            network: NetworkConfig {
+                hidden: vec![4],
+                ..Default::default()
+            },
+            epochs: 30,
+            optimizer: Optimizer::AdamW {
+                lr: 0.05,
+                weight_decay: 0.0,
+            },
+            min_accuracy: 0.0,
+            warm_start: false,
+            seed: Some(1),
+            ..Default::default()
+        };
+        let model = dir.with_extension("safetensors");
+        let outcome = train(dataset.unwrap(), &model, &config, &Device::Cpu);
+        let classified = classify(&model, &text, &Device::Cpu);
+        _ = std::fs::remove_file(&model);
+        outcome.unwrap();
+        let (file_type, confidence) = classified.unwrap();
+        assert_eq!(file_type, FileType::Text);
+        assert!(confidence > 0.0 && confidence <= 1.0);
+    }
+}
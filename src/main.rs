@@ -1,39 +1,66 @@
 use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Read},
     path::{Path, PathBuf},
     process::exit,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use binary_visualizer::{
-    ml::{train, Dataset, FileType, Network},
-    table::BinaryTable,
+    colormap::{render_diff_rgba, render_rgba, Colormap},
+    ml::{
+        argmax, classification_report, evaluate, log_confusion_matrix, parse_device,
+        read_extensions, sample_limit, seeded_rng, train, walk_dir, Dataset, DatasetConfig,
+        DatasetSummary, Ensemble, Evaluation, FileType, ModelInfo, Network, NetworkConfig,
+        PathFilter, TrainConfig,
+    },
+    table::{Axis, BinaryTable, BinaryTable3, ByteHistogram, Normalization},
 };
-use candle::Device;
-use clap::{arg, command, value_parser};
-use log::{error, info, LevelFilter};
+use candle::{DType, Device};
+use clap::{arg, command, value_parser, Arg, ArgAction, ArgMatches, Command};
+use glob::Pattern;
+use image::RgbaImage;
+use indicatif::ProgressBar;
+use log::{error, info, warn, LevelFilter};
 use macroquad::{
+    input::{
+        is_key_pressed, is_mouse_button_down, mouse_position, mouse_wheel, KeyCode, MouseButton,
+    },
     prelude::{Color, BLACK},
     shapes::draw_rectangle,
-    window::{clear_background, next_frame, Conf},
+    window::{clear_background, next_frame, screen_height, screen_width, Conf},
 };
 
-const SCALE: i32 = 4;
-const SCALEF: f32 = SCALE as f32;
+const MIN_ZOOM: f32 = 1.0;
+const MAX_ZOOM: f32 = 64.0;
+const ZOOM_STEP: f32 = 1.1;
+/// Keeps the window size of `256 * scale` pixels well within `i32`.
+const MAX_SCALE: i64 = 64;
+const WATCH_INTERVAL: Duration = Duration::from_millis(100);
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(250);
+/// The height of the tallest histogram bar in cells.
+const HISTOGRAM_HEIGHT: f32 = 64.0;
 
-fn config() -> Conf {
+fn config(scale: i32) -> Conf {
     Conf {
-        window_width: 256 * SCALE,
-        window_height: 256 * SCALE,
+        window_width: 256 * scale,
+        window_height: 256 * scale,
         ..Default::default()
     }
 }
 
-fn main() {
-    env_logger::builder()
-        .filter_level(LevelFilter::Info)
-        .format_timestamp(None)
-        .format_target(false)
-        .init();
-    let matches = command!()
+fn cli() -> Command {
+    command!()
+        .args([
+            arg!(-q --"quiet" "Only log warnings and errors")
+                .required(false)
+                .global(true)
+                .conflicts_with("verbose"),
+            arg!(-v --"verbose" "Also log debug messages")
+                .required(false)
+                .global(true),
+        ])
         .subcommands([
             command!("train").alias("t").args([
                 arg!(<MODEL> "The file the model is stored in")
@@ -42,69 +69,574 @@ fn main() {
                 arg!(<DATA> "The directory of the dataset to train on")
                     .required(true)
                     .value_parser(value_parser!(PathBuf)),
-                arg!(--"accuracy" "The minimum required accuracy expressed in percent (default: 95.0)")
+                arg!(--"accuracy" <PERCENT> "The minimum required accuracy expressed in percent (default: 95.0)")
                     .required(false)
-                    .value_parser(value_parser!(f32))
+                    .value_parser(parse_accuracy)
                     .default_value("95.0"),
-            ]),
+                arg!(--"split" <FRACTION> "The fraction of the dataset used for training (default: 0.8)")
+                    .required(false)
+                    .value_parser(value_parser!(f32))
+                    .default_value("0.8"),
+                arg!(--"batch-size" <N> "The number of files per training step (default: whole training set)")
+                    .required(false)
+                    .value_parser(value_parser!(usize)),
+                arg!(--"patience" <EPOCHS> "Stop after this many epochs without test loss improvement")
+                    .required(false)
+                    .value_parser(value_parser!(usize)),
+                arg!(--"device" <DEVICE> "The device to train on: cpu, cuda:N or metal (default: cpu)")
+                    .required(false)
+                    .default_value("cpu"),
+                arg!(--"sniff" "Check file signatures and relabel files whose extension disagrees")
+                    .required(false),
+                arg!(--"sniff-extensionless" "Label files without an extension as text or binary by their content")
+                    .required(false),
+                arg!(--"strict" "Skip files whose signature disagrees with their extension instead of relabeling them")
+                    .required(false),
+                arg!(--"no-follow-symlinks" "Skip symlinks instead of following them")
+                    .required(false),
+                arg!(--"dropout" <P> "The dropout probability after hidden layers while training (default: 0.0)")
+                    .required(false)
+                    .value_parser(value_parser!(f32))
+                    .default_value("0.0"),
+                arg!(--"weight-decay" <LAMBDA> "The L2 penalty on all weights (default: 0.0)")
+                    .required(false)
+                    .value_parser(value_parser!(f64))
+                    .default_value("0.0"),
+                arg!(--"max-grad-norm" <NORM> "Clip the global L2 norm of the gradients to NORM")
+                    .required(false)
+                    .value_parser(value_parser!(f64)),
+                arg!(--"from-scratch" "Ignore the weights already stored in MODEL")
+                    .required(false),
+                arg!(--"resume" <FROM> "Continue training from the weights of the model FROM, which must have the same layers")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf))
+                    .conflicts_with("from-scratch"),
+                arg!(--"balance" "Weight the loss of every class by its inverse frequency")
+                    .required(false),
+                arg!(--"max-retries" <N> "The maximum number of training attempts (default: 3)")
+                    .required(false)
+                    .value_parser(value_parser!(usize))
+                    .default_value("3"),
+                arg!(--"metrics" <FILE> "A CSV file to write per-epoch metrics to")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+                arg!(--"include-unknown" "Train files with unrecognized extensions as the Unknown class")
+                    .required(false),
+                arg!(--"cache-dir" <DIR> "A directory to cache parsed files in between runs")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+                arg!(--"sample-bytes" <N> "Only parse the first N bytes of every file, also when predicting")
+                    .required(false)
+                    .value_parser(value_parser!(usize)),
+                arg!(--"checkpoint-dir" <DIR> "Save the model to DIR/epoch_N.safetensors after every epoch")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+                arg!(--"keep-best" "Only keep the checkpoint with the highest test accuracy")
+                    .required(false)
+                    .requires("checkpoint-dir"),
+                arg!(--"valid-data" <DIR> "A separate directory to test on instead of splitting DATA")
+                    .required(false)
+                    .value_parser(value_parser!(PathBuf)),
+                arg!(--"normalization" <NAME> "How byte pair counts are scaled: log, linear or none (default: log)")
+                    .required(false)
+                    .value_parser(value_parser!(Normalization))
+                    .default_value("log"),
+                arg!(--"limit" <N> "The maximum number of files to load per class")
+                    .required(false)
+                    .value_parser(value_parser!(usize)),
+                arg!(--"dry-run" "Only print what the dataset contains")
+                    .required(false),
+                arg!(--"no-progress" "Do not show a progress bar while collecting the dataset")
+                    .required(false),
+                arg!(--"seed" <N> "Seeds the dataset split, batch order and weight initialization to make runs reproducible")
+                    .required(false)
+                    .value_parser(value_parser!(u64)),
+            ])
+            .args(labeling_args()),
             command!("predict").alias("p").args([
                 arg!(<MODEL> "The file the model is stored in")
                     .required(true)
                     .value_parser(value_parser!(PathBuf)),
-                arg!(<FILE> "The input file")
+                arg!(<FILE>... "The input files, or - for standard input")
                     .required(true)
-                    .value_parser(value_parser!(PathBuf))
+                    .value_parser(value_parser!(PathBuf)),
+                arg!(--"threshold" <P> "The minimum confidence below which the file type is unknown (default: 0.5)")
+                    .required(false)
+                    .value_parser(value_parser!(f32))
+                    .default_value("0.5"),
+                arg!(--"top" <K> "Also print the K most likely file types, as a \"top\" array with --format json")
+                    .required(false)
+                    .value_parser(value_parser!(usize)),
+                arg!(--"format" <FORMAT> "How to print the prediction: text or json (default: text)")
+                    .required(false)
+                    .value_parser(["text", "json"])
+                    .default_value("text"),
+                arg!(--"model" <FILE> "Another model to average the prediction with, can be repeated")
+                    .required(false)
+                    .action(ArgAction::Append)
+                    .value_parser(value_parser!(PathBuf)),
+                arg!(--"dtype" <DTYPE> "The precision to run the model in: f32, f16 or bf16 (default: f32)")
+                    .required(false)
+                    .value_parser(["f32", "f16", "bf16"])
+                    .default_value("f32"),
+            ]),
+            command!("predict-dir").alias("pd").args([
+                arg!(<MODEL> "The file the model is stored in")
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+                arg!(<DIR> "The directory of files to classify")
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+                arg!(--"json" "Print the results as a JSON array").required(false),
+                arg!(--"dtype" <DTYPE> "The precision to run the model in: f32, f16 or bf16 (default: f32)")
+                    .required(false)
+                    .value_parser(["f32", "f16", "bf16"])
+                    .default_value("f32"),
+            ]),
+            command!("scan").args([
+                arg!(<MODEL> "The file the model is stored in")
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+                arg!(<FILE> "The input file, or - for standard input")
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+                arg!(--"window" <BYTES> "The size of every classified region (default: 4096)")
+                    .required(false)
+                    .value_parser(value_parser!(usize))
+                    .default_value("4096"),
+                arg!(--"step" <BYTES> "The distance between the starts of two regions (default: 2048)")
+                    .required(false)
+                    .value_parser(value_parser!(usize))
+                    .default_value("2048"),
+            ]),
+            command!("eval").alias("v").args([
+                arg!(<MODEL> "The file the model is stored in")
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+                arg!(<DATA> "The directory of the labeled dataset to evaluate on")
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+            ])
+            .args(labeling_args()),
+            command!("show").alias("s").args([
+                arg!(<FILE> "The input file, or - for standard input")
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+                arg!(--"colormap" <NAME> "The colormap to draw with: green, grayscale, viridis or inferno (default: green)")
+                    .required(false)
+                    .value_parser(value_parser!(Colormap))
+                    .default_value("green"),
+                arg!(--"scale" <N> "The size of every cell in pixels, at most 64 (default: 4)")
+                    .required(false)
+                    .value_parser(value_parser!(i32).range(1..=MAX_SCALE))
+                    .default_value("4"),
+                arg!(--"mask-zero" "Hide the (0, 0) pair that dominates zero-padded files")
+                    .required(false),
+                arg!(--"clip" <PERCENTILE> "Scale colors up to this percentile of the pair counts instead of the maximum")
+                    .required(false)
+                    .value_parser(value_parser!(f32)),
+                arg!(--"normalize" <MODE> "How pair counts are mapped to colors: log, linear, equalize, row or col (default: log)")
+                    .required(false)
+                    .value_parser(["log", "linear", "equalize", "row", "col"])
+                    .default_value("log")
+                    .conflicts_with("clip"),
+                arg!(--"watch" "Reload the file whenever it changes")
+                    .required(false),
+                arg!(--"histogram" "Overlay a log-scaled histogram of single byte values along the bottom edge")
+                    .required(false),
             ]),
-            command!("show")
-                .alias("s")
-                .args([arg!(<FILE> "The input file")
+            command!("diff").alias("d").args([
+                arg!(<FILE_A> "The first input file, red where it has more transitions")
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+                arg!(<FILE_B> "The second input file, green where it has more transitions")
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+                arg!(--"scale" <N> "The size of every cell in pixels, at most 64 (default: 4)")
+                    .required(false)
+                    .value_parser(value_parser!(i32).range(1..=MAX_SCALE))
+                    .default_value("4"),
+            ]),
+            command!("export").alias("e").args([
+                arg!(<FILE> "The input file, or - for standard input")
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+                arg!(<OUT> "The PNG file to write")
                     .required(true)
-                    .value_parser(value_parser!(PathBuf))]),
+                    .value_parser(value_parser!(PathBuf)),
+                arg!(--"triples" "Color every byte pair by the byte that follows it")
+                    .required(false),
+                arg!(--"svg" "Write an SVG instead of a PNG")
+                    .required(false)
+                    .conflicts_with("triples"),
+            ]),
+            command!("dump").args([
+                arg!(<FILE> "The input file, or - for standard input")
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+                arg!(<OUT> "The CSV file to write the raw byte pair counts to")
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+            ]),
+            command!("info").args([arg!(<MODEL> "The model to describe")
+                .required(true)
+                .value_parser(value_parser!(PathBuf))]),
         ])
         .subcommand_required(true)
-        .get_matches();
+}
+
+fn main() {
+    let matches = cli().get_matches();
+    env_logger::builder()
+        .filter_level(level_from_flags(
+            matches.get_flag("quiet"),
+            matches.get_flag("verbose"),
+        ))
+        .format_timestamp(None)
+        .format_target(false)
+        .parse_default_env()
+        .init();
     match matches.subcommand() {
         Some(("train", args)) => {
             let model = args.get_one::<PathBuf>("MODEL").unwrap();
             let data = args.get_one::<PathBuf>("DATA").unwrap();
             let &accuracy = args.get_one::<f32>("accuracy").unwrap();
+            let &split = args.get_one::<f32>("split").unwrap();
+            let &max_retries = args.get_one::<usize>("max-retries").unwrap();
+            let batch_size = args.get_one::<usize>("batch-size").copied();
+            let patience = args.get_one::<usize>("patience").copied();
+            let &dropout = args.get_one::<f32>("dropout").unwrap();
+            let &weight_decay = args.get_one::<f64>("weight-decay").unwrap();
+            let warm_start = !args.get_flag("from-scratch");
+            let resume_from = args.get_one::<PathBuf>("resume").cloned();
+            let max_grad_norm = args.get_one::<f64>("max-grad-norm").copied();
+            let device = args.get_one::<String>("device").unwrap();
+            let strict = args.get_flag("strict");
+            let sniff_magic = args.get_flag("sniff") || strict;
+            let sniff_extensionless = args.get_flag("sniff-extensionless");
+            let follow_symlinks = !args.get_flag("no-follow-symlinks");
+            let balance = args.get_flag("balance");
+            let metrics_path = args.get_one::<PathBuf>("metrics").cloned();
+            let max_per_class = args.get_one::<usize>("limit").copied();
+            let seed = args.get_one::<u64>("seed").copied();
+            let dry_run = args.get_flag("dry-run");
+            let show_progress = !args.get_flag("no-progress");
+            let cache_dir = args.get_one::<PathBuf>("cache-dir").cloned();
+            let include_unknown = args.get_flag("include-unknown");
+            let valid_dir = args.get_one::<PathBuf>("valid-data").cloned();
+            let sample_bytes = args.get_one::<usize>("sample-bytes").copied();
+            let checkpoint_dir = args.get_one::<PathBuf>("checkpoint-dir").cloned();
+            let keep_best = args.get_flag("keep-best");
+            let filter = path_filter(args);
+            let &normalization = args.get_one::<Normalization>("normalization").unwrap();
             if !data.exists() || !data.is_dir() {
                 error!("The dataset does not exist or is not a directory");
                 exit(1);
             }
-            if accuracy < 1.0 {
-                error!("Minimum accuracy cannot be below 1%");
+            if sample_bytes.is_some_and(|sample_bytes| sample_bytes < 2) {
+                error!("Sample size must be at least 2 bytes to contain a byte pair");
+                exit(1);
+            }
+            if valid_dir.as_ref().is_some_and(|dir| !dir.is_dir()) {
+                error!("The validation dataset does not exist or is not a directory");
+                exit(1);
+            }
+            if resume_from.as_ref().is_some_and(|model| !model.is_file()) {
+                error!("The model to resume from does not exist or is not a file");
+                exit(1);
+            }
+            if !(0.0..1.0).contains(&dropout) {
+                error!("Dropout must be at least 0 and below 1");
+                exit(1);
+            }
+            if max_grad_norm.is_some_and(|norm| norm <= 0.0) {
+                error!("Maximum gradient norm must be positive");
+                exit(1);
+            }
+            if weight_decay < 0.0 {
+                error!("Weight decay cannot be negative");
                 exit(1);
             }
+            if max_retries == 0 {
+                error!("At least one training attempt is required");
+                exit(1);
+            }
+            let dev = match parse_device(device) {
+                Ok(dev) => dev,
+                Err(err) => {
+                    warn!("Could not create device {device:?}, falling back to CPU - {err}");
+                    Device::Cpu
+                }
+            };
+            let extensions = extension_mapping(args);
+            let dataset_config = DatasetConfig {
+                split,
+                sniff_magic,
+                strict,
+                follow_symlinks,
+                max_per_class,
+                seed,
+                extensions,
+                cache_dir,
+                normalization,
+                include_unknown,
+                valid_dir,
+                sample_bytes,
+                filter,
+                sniff_extensionless,
+            };
+            if dry_run {
+                match Dataset::summarize(data, &dataset_config) {
+                    Ok(summary) => log_summary(&summary),
+                    Err(err) => {
+                        error!("Could not summarize dataset - {err}");
+                        exit(1);
+                    }
+                }
+                return;
+            }
             info!("Collecting dataset...");
-            let ds = match Dataset::collect(data, &Device::Cpu) {
+            let bar = if show_progress {
+                ProgressBar::new(0)
+            } else {
+                ProgressBar::hidden()
+            };
+            let ds = Dataset::collect_with_progress(data, &dataset_config, &dev, |_, total| {
+                bar.set_length(total as u64);
+                bar.inc(1);
+            });
+            bar.finish_and_clear();
+            let ds = match ds {
                 Ok(ds) => ds,
                 Err(err) => {
                     error!("Could not collect dataset - {err}");
                     exit(1);
                 }
             };
-            info!("Start training...");
-            let _trained_model = loop {
-                match train(ds.clone(), model, &Device::Cpu) {
-                    Ok(model) => {
-                        break model;
-                    }
+            if ds.unreadable > 0 {
+                warn!("Skipped {} unreadable files", ds.unreadable);
+            }
+            let class_weights = if balance {
+                match ds.class_weights() {
+                    Ok(weights) => Some(weights),
                     Err(err) => {
-                        error!("{err}");
+                        error!("Could not compute class weights - {err}");
+                        exit(1);
                     }
                 }
+            } else {
+                None
             };
+            let config = TrainConfig {
+                network: NetworkConfig {
+                    dropout,
+                    ..Default::default()
+                },
+                min_accuracy: accuracy,
+                batch_size,
+                patience,
+                class_weights,
+                metrics_path,
+                weight_decay,
+                warm_start,
+                resume_from,
+                max_grad_norm,
+                seed,
+                checkpoint_dir,
+                keep_best,
+                ..Default::default()
+            };
+            info!("Start training...");
+            let mut rng = seeded_rng(seed);
+            let trained_model = retry(max_retries, |attempt| {
+                let ds = if attempt == 1 {
+                    ds.clone()
+                } else {
+                    ds.reshuffle(&mut rng)?
+                };
+                Ok(train(ds, model, &config, &dev)?)
+            });
+            if trained_model.is_err() {
+                error!("Giving up after {max_retries} attempts");
+                exit(1);
+            }
             info!("Model successully trained");
         }
         Some(("predict", args)) => {
+            let mut models = vec![args.get_one::<PathBuf>("MODEL").unwrap()];
+            models.extend(args.get_many::<PathBuf>("model").into_iter().flatten());
+            let files = args
+                .get_many::<PathBuf>("FILE")
+                .unwrap()
+                .collect::<Vec<_>>();
+            let &threshold = args.get_one::<f32>("threshold").unwrap();
+            let top = args.get_one::<usize>("top").copied();
+            let json = args.get_one::<String>("format").unwrap() == "json";
+            let dtype: DType = args.get_one::<String>("dtype").unwrap().parse().unwrap();
+            for model in &models {
+                if !model.exists() || !model.is_file() {
+                    error!("Model {model:?} does not exist or is not a file");
+                    exit(1);
+                }
+            }
+            let dev = match Device::cuda_if_available(0) {
+                Ok(dev) => dev,
+                Err(err) => {
+                    error!("Could not create device: {err}");
+                    exit(1);
+                }
+            };
+            let model =
+                match Ensemble::load(&models, &dev).and_then(|model| model.to_dtype(dtype, &dev)) {
+                    Ok(model) => model,
+                    Err(err) => {
+                        error!("Could not load model: {err}");
+                        exit(1);
+                    }
+                };
+            let mut table = BinaryTable::new();
+            // A failing file is reported and skipped, so the remaining files are still predicted.
+            let mut failed = 0;
+            for file in files.iter().copied() {
+                if !is_stdin(file) && (!file.exists() || !file.is_file()) {
+                    error!("Input {file:?} does not exist or is not a file");
+                    failed += 1;
+                    continue;
+                }
+                let content = match read_input(file) {
+                    Ok(content) => content,
+                    Err(err) => {
+                        error!("Could not read input file {file:?}: {err}");
+                        failed += 1;
+                        continue;
+                    }
+                };
+                table.clear();
+                table.parse(model.sample(&content));
+                let probabilities = match model.predict(&table, &dev) {
+                    Ok(probabilities) => probabilities,
+                    Err(err) => {
+                        error!("Could not predict file type of {file:?}: {err}");
+                        failed += 1;
+                        continue;
+                    }
+                };
+                let (prediction, confidence) = argmax(&probabilities);
+                let file_type = FileType::from_prediction_with_threshold(
+                    &probabilities,
+                    model.classes(),
+                    threshold,
+                );
+                let ranked = match top.map(|k| model.predict_topk(&table, &dev, k)) {
+                    Some(Ok(ranked)) => Some(ranked),
+                    Some(Err(err)) => {
+                        error!("Could not predict file type of {file:?}: {err}");
+                        failed += 1;
+                        continue;
+                    }
+                    None => None,
+                };
+                if json {
+                    let mut result = prediction_json(
+                        file,
+                        file_type,
+                        confidence,
+                        &probabilities,
+                        model.classes(),
+                    );
+                    if let Some(ranked) = &ranked {
+                        result["top"] = ranking_json(ranked);
+                    }
+                    println!("{result}");
+                    continue;
+                }
+                // A single file keeps the output short, several files are told apart by path.
+                let prefix = if files.len() > 1 {
+                    format!("{file:?}: ")
+                } else {
+                    String::new()
+                };
+                let confidence = confidence * 100.0;
+                match file_type {
+                    Some(file_type) => {
+                        info!("{prefix}{prediction} - {file_type:?} ({confidence:.2}%)")
+                    }
+                    None => info!("{prefix}{prediction} - unknown ({confidence:.2}%)"),
+                }
+                if let Some(ranked) = ranked {
+                    for (rank, (file_type, p)) in ranked.into_iter().enumerate() {
+                        info!("{}. {file_type:?} ({:.2}%)", rank + 1, p * 100.0);
+                    }
+                }
+            }
+            if failed > 0 {
+                error!("Could not predict {failed} of {} files", files.len());
+                exit(1);
+            }
+        }
+        Some(("predict-dir", args)) => {
+            let model = args.get_one::<PathBuf>("MODEL").unwrap();
+            let dir = args.get_one::<PathBuf>("DIR").unwrap();
+            let json = args.get_flag("json");
+            let dtype: DType = args.get_one::<String>("dtype").unwrap().parse().unwrap();
+            if !model.exists() || !model.is_file() {
+                error!("Model does not exist or is not a file");
+                exit(1);
+            }
+            if !dir.exists() || !dir.is_dir() {
+                error!("Input does not exist or is not a directory");
+                exit(1);
+            }
+            let dev = match Device::cuda_if_available(0) {
+                Ok(dev) => dev,
+                Err(err) => {
+                    error!("Could not create device: {err}");
+                    exit(1);
+                }
+            };
+            let model = match Network::load_as(model, &dev, dtype) {
+                Ok(model) => model,
+                Err(err) => {
+                    error!("Could not load model: {err}");
+                    exit(1);
+                }
+            };
+            let mut table = BinaryTable::new();
+            let mut results = Vec::new();
+            let walked = walk_dir(dir, true, &PathFilter::default(), &mut |path| {
+                match predict_file(&model, &mut table, &path, &dev) {
+                    Ok((file_type, confidence)) if json => {
+                        results.push(serde_json::json!({
+                            "path": path.to_string_lossy(),
+                            "type": format!("{file_type:?}"),
+                            "confidence": confidence,
+                        }));
+                    }
+                    Ok((file_type, confidence)) => {
+                        println!("{path:?} -> {file_type:?} -> {:.2}%", confidence * 100.0);
+                    }
+                    Err(err) => warn!("Skipping {path:?} - {err}"),
+                }
+                Ok(())
+            });
+            if let Err(err) = walked {
+                error!("Could not walk directory: {err}");
+                exit(1);
+            }
+            if json {
+                println!("{}", serde_json::Value::Array(results));
+            }
+        }
+        Some(("scan", args)) => {
             let model = args.get_one::<PathBuf>("MODEL").unwrap();
             let file = args.get_one::<PathBuf>("FILE").unwrap();
+            let &window = args.get_one::<usize>("window").unwrap();
+            let &step = args.get_one::<usize>("step").unwrap();
             if !model.exists() || !model.is_file() {
                 error!("Model does not exist or is not a file");
                 exit(1);
             }
-            if !file.exists() || !file.is_file() {
+            if !is_stdin(file) && (!file.exists() || !file.is_file()) {
                 error!("Input does not exist or is not a file");
                 exit(1);
             }
@@ -122,59 +654,826 @@ fn main() {
                     exit(1);
                 }
             };
-            let content = match std::fs::read(file) {
+            let content = match read_input(file) {
                 Ok(content) => content,
                 Err(err) => {
                     error!("Could not read input file: {err}");
                     exit(1);
                 }
             };
-            let mut table = BinaryTable::new();
-            table.parse(&content);
-            let prediction = match model.predict(&table, &dev) {
-                Ok(prediction) => prediction,
+            let regions = match model.scan(&content, window, step, &dev) {
+                Ok(regions) => regions,
                 Err(err) => {
-                    error!("Could not predict file type: {err}");
+                    error!("Could not scan file: {err}");
                     exit(1);
                 }
             };
-            let file_type = FileType::from_prediction(prediction);
-            info!("{prediction:?} - {file_type:?}");
+            for (offset, file_type, confidence) in regions {
+                println!("{offset:#010x} {file_type:?} {:.2}%", confidence * 100.0);
+            }
+        }
+        Some(("eval", args)) => {
+            let model = args.get_one::<PathBuf>("MODEL").unwrap();
+            let data = args.get_one::<PathBuf>("DATA").unwrap();
+            if !model.exists() || !model.is_file() {
+                error!("Model does not exist or is not a file");
+                exit(1);
+            }
+            if !data.exists() || !data.is_dir() {
+                error!("The dataset does not exist or is not a directory");
+                exit(1);
+            }
+            let dev = match Device::cuda_if_available(0) {
+                Ok(dev) => dev,
+                Err(err) => {
+                    error!("Could not create device: {err}");
+                    exit(1);
+                }
+            };
+            let model = match Network::load(model, &dev) {
+                Ok(model) => model,
+                Err(err) => {
+                    error!("Could not load model: {err}");
+                    exit(1);
+                }
+            };
+            let config = DatasetConfig {
+                extensions: extension_mapping(args),
+                filter: path_filter(args),
+                ..Default::default()
+            };
+            let evaluation = match evaluate(&model, data, &config, &dev) {
+                Ok(evaluation) => evaluation,
+                Err(err) => {
+                    error!("Could not evaluate model: {err}");
+                    exit(1);
+                }
+            };
+            log_evaluation(&evaluation);
         }
         Some(("show", args)) => {
             let file = args.get_one::<PathBuf>("FILE").unwrap();
-            macroquad::Window::from_config(config(), window(file.clone()));
+            let &colormap = args.get_one::<Colormap>("colormap").unwrap();
+            let &scale = args.get_one::<i32>("scale").unwrap();
+            let clip = args.get_one::<f32>("clip").copied();
+            let mask_zero = args.get_flag("mask-zero");
+            let normalize = args.get_one::<String>("normalize").unwrap();
+            let watch = args.get_flag("watch");
+            let histogram = args.get_flag("histogram");
+            if !is_stdin(file) && (!file.exists() || !file.is_file()) {
+                error!("Input does not exist or is not a file");
+                exit(1);
+            }
+            if watch && is_stdin(file) {
+                error!("Standard input cannot be watched");
+                exit(1);
+            }
+            if clip.is_some_and(|clip| !(clip > 0.0 && clip <= 100.0)) {
+                error!("Clip percentile must be above 0 and at most 100");
+                exit(1);
+            }
+            let content = match read_input(file) {
+                Ok(content) => content,
+                Err(err) => {
+                    error!("Could not read input file: {err}");
+                    exit(1);
+                }
+            };
+            let options = ShowOptions {
+                colormap,
+                clip,
+                normalize: normalize.clone(),
+                mask_zero,
+                histogram,
+            };
+            let frame = options.render(&content);
+            let watcher = watch.then(|| Watcher::new(file.clone(), options));
+            macroquad::Window::from_config(config(scale), window(frame, scale as f32, watcher));
+        }
+        Some(("diff", args)) => {
+            let file_a = args.get_one::<PathBuf>("FILE_A").unwrap();
+            let file_b = args.get_one::<PathBuf>("FILE_B").unwrap();
+            let &scale = args.get_one::<i32>("scale").unwrap();
+            let mut tables = [BinaryTable::new(), BinaryTable::new()];
+            for (table, file) in tables.iter_mut().zip([file_a, file_b]) {
+                if !is_stdin(file) && (!file.exists() || !file.is_file()) {
+                    error!("Input {} does not exist or is not a file", file.display());
+                    exit(1);
+                }
+                match read_input(file) {
+                    Ok(content) => table.parse(&content),
+                    Err(err) => {
+                        error!("Could not read input file {}: {err}", file.display());
+                        exit(1);
+                    }
+                }
+            }
+            let [a, b] = tables;
+            let frame = Frame {
+                pixels: render_diff_rgba(&a.diff(&b)),
+                histogram: None,
+            };
+            macroquad::Window::from_config(config(scale), window(frame, scale as f32, None));
+        }
+        Some(("export", args)) => {
+            let file = args.get_one::<PathBuf>("FILE").unwrap();
+            let out = args.get_one::<PathBuf>("OUT").unwrap();
+            let triples = args.get_flag("triples");
+            let svg = args.get_flag("svg");
+            if !is_stdin(file) && (!file.exists() || !file.is_file()) {
+                error!("Input does not exist or is not a file");
+                exit(1);
+            }
+            let content = match read_input(file) {
+                Ok(content) => content,
+                Err(err) => {
+                    error!("Could not read input file: {err}");
+                    exit(1);
+                }
+            };
+            if svg {
+                let mut table = BinaryTable::new();
+                table.parse(&content);
+                if let Err(err) = std::fs::write(out, table.to_svg()) {
+                    error!("Could not write image: {err}");
+                    exit(1);
+                }
+                info!("Image written to {out:?}");
+                return;
+            }
+            let image = if triples {
+                let mut table = BinaryTable3::new();
+                table.parse(&content);
+                table.to_image()
+            } else {
+                let mut table = BinaryTable::new();
+                table.parse(&content);
+                table.to_image()
+            };
+            if let Err(err) = image.save(out) {
+                error!("Could not write image: {err}");
+                exit(1);
+            }
+            info!("Image written to {out:?}");
+        }
+        Some(("dump", args)) => {
+            let file = args.get_one::<PathBuf>("FILE").unwrap();
+            let out = args.get_one::<PathBuf>("OUT").unwrap();
+            if !is_stdin(file) && (!file.exists() || !file.is_file()) {
+                error!("Input does not exist or is not a file");
+                exit(1);
+            }
+            let content = match read_input(file) {
+                Ok(content) => content,
+                Err(err) => {
+                    error!("Could not read input file: {err}");
+                    exit(1);
+                }
+            };
+            let mut table = BinaryTable::new();
+            table.parse(&content);
+            let written = File::create(out)
+                .map_err(Into::into)
+                .and_then(|file| table.write_csv(BufWriter::new(file)));
+            if let Err(err) = written {
+                error!("Could not write counts: {err}");
+                exit(1);
+            }
+            info!("Counts written to {out:?}");
+        }
+        Some(("info", args)) => {
+            let model = args.get_one::<PathBuf>("MODEL").unwrap();
+            let info = match ModelInfo::read(model) {
+                Ok(info) => info,
+                Err(err) => {
+                    error!("Could not read model: {err}");
+                    exit(1);
+                }
+            };
+            let unknown = || "unknown".to_string();
+            let layers: Vec<String> = info.layers.iter().map(ToString::to_string).collect();
+            println!("Version:       {}", info.version.unwrap_or_else(unknown));
+            println!(
+                "Architecture:  {}",
+                info.architecture.unwrap_or_else(unknown)
+            );
+            println!("Layers:        {}", layers.join(", "));
+            println!("Parameters:    {}", info.parameters);
+            println!("Normalization: {}", info.normalization.name());
+            match info.sample_bytes {
+                Some(sample_bytes) => println!("Sample bytes:  {sample_bytes}"),
+                None => println!("Sample bytes:  all"),
+            }
+            println!(
+                "Class scheme:  {}",
+                info.class_scheme.unwrap_or_else(unknown)
+            );
+            match info.classes {
+                Some(classes) => println!("Classes:       {}", classes.join(", ")),
+                None => println!("Classes:       unknown"),
+            }
         }
         _ => unreachable!(),
     }
 }
 
-async fn window<P>(path: P)
+/// Parses the `--accuracy` percentage, which has to be between 1 and 100.
+/// How `train` and `eval` pick and label the files below DATA, so a model is scored against the
+/// labels it was trained with.
+fn labeling_args() -> [Arg; 4] {
+    [
+        arg!(--"include" <GLOB> "Only collect files whose path below DATA matches, can be repeated")
+            .required(false)
+            .action(ArgAction::Append)
+            .value_parser(value_parser!(Pattern)),
+        arg!(--"exclude" <GLOB> "Skip files whose path below DATA matches, e.g. '**/.git/**', can be repeated")
+            .required(false)
+            .action(ArgAction::Append)
+            .value_parser(value_parser!(Pattern)),
+        arg!(--"hidden" "Also collect hidden files and directories, whose names start with a dot")
+            .required(false),
+        arg!(--"extensions" <FILE> "A JSON file mapping extensions to file types, e.g. {\"dat\": \"Binary\"}")
+            .required(false)
+            .value_parser(value_parser!(PathBuf)),
+    ]
+}
+
+fn path_filter(args: &ArgMatches) -> PathFilter {
+    let patterns = |id| {
+        args.get_many::<Pattern>(id)
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect()
+    };
+    PathFilter {
+        include: patterns("include"),
+        exclude: patterns("exclude"),
+        hidden: args.get_flag("hidden"),
+    }
+}
+
+fn extension_mapping(args: &ArgMatches) -> HashMap<String, FileType> {
+    match args.get_one::<PathBuf>("extensions").map(read_extensions) {
+        Some(Ok(extensions)) => extensions,
+        Some(Err(err)) => {
+            error!("Could not read extension mapping - {err}");
+            exit(1);
+        }
+        None => HashMap::new(),
+    }
+}
+
+fn parse_accuracy(value: &str) -> Result<f32, String> {
+    let accuracy: f32 = value.parse().map_err(|err| format!("{err}"))?;
+    if (1.0..=100.0).contains(&accuracy) {
+        Ok(accuracy)
+    } else {
+        Err("minimum accuracy must be between 1% and 100%".to_string())
+    }
+}
+
+fn level_from_flags(quiet: bool, verbose: bool) -> LevelFilter {
+    if quiet {
+        LevelFilter::Warn
+    } else if verbose {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    }
+}
+
+fn log_evaluation(evaluation: &Evaluation) {
+    let confusion = &evaluation.confusion;
+    log_confusion_matrix(confusion);
+    let report = classification_report(confusion);
+    info!(
+        "{:>8}{:>11}{:>11}{:>11}{:>9}",
+        "", "Precision", "Recall", "F1", "Support"
+    );
+    let rows = FileType::ALL
+        .iter()
+        .map(|typ| typ.name())
+        .zip(&report.classes)
+        .chain([
+            ("Macro", &report.macro_avg),
+            ("Weighted", &report.weighted_avg),
+        ]);
+    for (name, metrics) in rows {
+        info!(
+            "{name:>8}{:>10.2}%{:>10.2}%{:>10.2}%{:>9}",
+            metrics.precision * 100.0,
+            metrics.recall * 100.0,
+            metrics.f1 * 100.0,
+            metrics.support
+        );
+    }
+    info!("Accuracy: {:.2}%", evaluation.accuracy());
+    if evaluation.skipped > 0 {
+        info!(
+            "Skipped {} files with unknown extensions",
+            evaluation.skipped
+        );
+    }
+    if evaluation.unreadable > 0 {
+        warn!("Skipped {} unreadable files", evaluation.unreadable);
+    }
+}
+
+fn is_stdin(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+/// Reads the whole input, treating `-` as standard input.
+fn read_input(path: &Path) -> std::io::Result<Vec<u8>> {
+    read_input_from(path, std::io::stdin().lock())
+}
+
+/// Like `read_input`, with `stdin` standing in for standard input.
+fn read_input_from<R>(path: &Path, mut stdin: R) -> std::io::Result<Vec<u8>>
 where
-    P: AsRef<Path>,
+    R: Read,
 {
-    let bytes = std::fs::read(&path).expect("Read from input file");
-    let mut table = BinaryTable::new();
-    table.parse(&bytes);
-    let export = table.export();
+    if is_stdin(path) {
+        let mut bytes = Vec::new();
+        stdin.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    } else {
+        std::fs::read(path)
+    }
+}
+
+/// The `predict --format json` object for one file, with `probabilities` indexed like `classes`.
+fn prediction_json(
+    file: &Path,
+    file_type: Option<FileType>,
+    confidence: f32,
+    probabilities: &[f32],
+    classes: &[FileType],
+) -> serde_json::Value {
+    let probabilities: serde_json::Map<_, _> = classes
+        .iter()
+        .zip(probabilities)
+        .map(|(file_type, &p)| (format!("{file_type:?}"), p.into()))
+        .collect();
+    serde_json::json!({
+        "file": file.to_string_lossy(),
+        "type": file_type.map(|file_type| format!("{file_type:?}")),
+        "confidence": confidence,
+        "probabilities": probabilities,
+    })
+}
+
+/// The `--top` ranking as an array of `{"type", "probability"}` objects, most likely first.
+fn ranking_json(ranked: &[(FileType, f32)]) -> serde_json::Value {
+    ranked
+        .iter()
+        .map(|(file_type, p)| {
+            serde_json::json!({
+                "type": format!("{file_type:?}"),
+                "probability": p,
+            })
+        })
+        .collect()
+}
+
+fn predict_file(
+    model: &Network,
+    table: &mut BinaryTable,
+    path: &Path,
+    dev: &Device,
+) -> anyhow::Result<(FileType, f32)> {
+    table.clear();
+    table.parse_reader(File::open(path)?.take(sample_limit(model.config.sample_bytes)))?;
+    let probabilities = model.predict_probabilities(table, dev)?;
+    let (output, confidence) = argmax(&probabilities);
+    let file_type = model
+        .file_type(output)
+        .ok_or_else(|| anyhow::Error::msg(format!("Unknown prediction {output}")))?;
+    Ok((file_type, confidence))
+}
+
+fn log_summary(summary: &DatasetSummary) {
+    for (file_type, count) in FileType::ALL.iter().zip(summary.counts) {
+        info!("{file_type:?}: {count} files");
+    }
+    info!("Skipped: {} files", summary.skipped);
+    info!("Total size: {} bytes", summary.bytes);
+    info!(
+        "Train: {} files, test: {} files",
+        summary.train_len, summary.test_len
+    );
+}
+
+fn retry<T, F>(attempts: usize, mut f: F) -> anyhow::Result<T>
+where
+    F: FnMut(usize) -> anyhow::Result<T>,
+{
+    let mut result = Err(anyhow::Error::msg("No attempts were made"));
+    for attempt in 1..=attempts {
+        result = f(attempt);
+        match &result {
+            Ok(_) => break,
+            Err(err) => error!("Attempt {attempt}/{attempts} failed - {err}"),
+        }
+    }
+    result
+}
+
+/// The visible region of the table: `x`/`y` is the unzoomed window position shown in the top
+/// left corner.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Camera {
+    x: f32,
+    y: f32,
+    zoom: f32,
+}
+
+impl Camera {
+    fn to_screen(self, x: f32, y: f32) -> (f32, f32) {
+        ((x - self.x) * self.zoom, (y - self.y) * self.zoom)
+    }
+
+    /// Scales the zoom by `factor` while keeping the point under `(sx, sy)` in place.
+    fn zoom_at(self, factor: f32, sx: f32, sy: f32) -> Self {
+        let zoom = (self.zoom * factor).clamp(MIN_ZOOM, MAX_ZOOM);
+        Self {
+            x: self.x + sx / self.zoom - sx / zoom,
+            y: self.y + sy / self.zoom - sy / zoom,
+            zoom,
+        }
+    }
+
+    fn pan(self, dx: f32, dy: f32) -> Self {
+        Self {
+            x: self.x - dx / self.zoom,
+            y: self.y - dy / self.zoom,
+            ..self
+        }
+    }
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self {
+            x: 0.0,
+            y: 0.0,
+            zoom: MIN_ZOOM,
+        }
+    }
+}
+
+/// How `show` turns the content of a file into pixels.
+struct ShowOptions {
+    colormap: Colormap,
+    clip: Option<f32>,
+    normalize: String,
+    mask_zero: bool,
+    histogram: bool,
+}
+
+/// What the window draws: the table pixels and optionally the byte histogram bars.
+struct Frame {
+    pixels: Vec<u8>,
+    histogram: Option<Vec<f32>>,
+}
+
+impl ShowOptions {
+    fn render(&self, content: &[u8]) -> Frame {
+        let mut table = BinaryTable::new();
+        table.parse(content);
+        let export = match (self.clip, self.normalize.as_str()) {
+            (None, "linear") => table.export_masked(Normalization::Linear, self.mask_zero),
+            (None, "log") => table.export_masked(Normalization::Log, self.mask_zero),
+            (clip, normalize) => {
+                let masked;
+                let table = if self.mask_zero {
+                    masked = table.without_zero_pair();
+                    &masked
+                } else {
+                    &table
+                };
+                match (clip, normalize) {
+                    (Some(clip), _) => table.export_clipped(clip),
+                    (None, "equalize") => table.export_equalized(),
+                    (None, "row") => table.export_normalized(Axis::Row),
+                    (None, "col") => table.export_normalized(Axis::Col),
+                    (None, _) => table.export(),
+                }
+            }
+        };
+        let histogram = self.histogram.then(|| {
+            let mut histogram = ByteHistogram::new();
+            histogram.parse(content);
+            histogram.export_with(Normalization::Log)
+        });
+        Frame {
+            pixels: render_rgba(&export, self.colormap),
+            histogram,
+        }
+    }
+}
+
+/// Re-renders a file once its modification time stopped changing for `WATCH_DEBOUNCE`.
+struct Watcher {
+    path: PathBuf,
+    options: ShowOptions,
+    modified: Option<SystemTime>,
+    changed_at: Option<Instant>,
+    polled_at: Instant,
+}
+
+impl Watcher {
+    fn new(path: PathBuf, options: ShowOptions) -> Self {
+        let modified = modified(&path);
+        Self {
+            path,
+            options,
+            modified,
+            changed_at: None,
+            polled_at: Instant::now(),
+        }
+    }
+
+    /// Returns the new frame if the file changed since the last reload.
+    fn poll(&mut self) -> Option<Frame> {
+        if self.polled_at.elapsed() < WATCH_INTERVAL {
+            return None;
+        }
+        self.polled_at = Instant::now();
+        let modified = modified(&self.path);
+        if modified != self.modified {
+            self.modified = modified;
+            self.changed_at = Some(Instant::now());
+            return None;
+        }
+        if self
+            .changed_at
+            .is_none_or(|changed_at| changed_at.elapsed() < WATCH_DEBOUNCE)
+        {
+            return None;
+        }
+        self.changed_at = None;
+        match std::fs::read(&self.path) {
+            Ok(content) => {
+                info!("Reloaded {:?}", self.path);
+                Some(self.options.render(&content))
+            }
+            Err(err) => {
+                warn!("Could not reload {:?} - {err}", self.path);
+                None
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+async fn window(mut frame: Frame, scale: f32, mut watcher: Option<Watcher>) {
+    let mut camera = Camera::default();
+    let mut drag = None;
     loop {
-        draw(&export);
+        if let Some(reloaded) = watcher.as_mut().and_then(Watcher::poll) {
+            frame = reloaded;
+        }
+        let (mx, my) = mouse_position();
+        let (_, wheel) = mouse_wheel();
+        if wheel > 0.0 {
+            camera = camera.zoom_at(ZOOM_STEP, mx, my);
+        } else if wheel < 0.0 {
+            camera = camera.zoom_at(1.0 / ZOOM_STEP, mx, my);
+        }
+        if is_mouse_button_down(MouseButton::Left) {
+            if let Some((px, py)) = drag {
+                camera = camera.pan(mx - px, my - py);
+            }
+            drag = Some((mx, my));
+        } else {
+            drag = None;
+        }
+        if is_key_pressed(KeyCode::R) {
+            camera = Camera::default();
+        }
+        if is_key_pressed(KeyCode::S) {
+            match save_capture(&frame.pixels, Path::new(".")) {
+                Ok(path) => info!("Capture written to {path:?}"),
+                Err(err) => error!("Could not write capture: {err}"),
+            }
+        }
+        draw(&frame.pixels, camera, scale);
+        if let Some(histogram) = &frame.histogram {
+            draw_histogram(histogram, scale);
+        }
         next_frame().await
     }
 }
 
-fn draw(table: &[f32]) {
+/// Writes the unzoomed 256×256 RGBA pixels to a PNG named after the current time in `dir`.
+fn save_capture(pixels: &[u8], dir: &Path) -> anyhow::Result<PathBuf> {
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    let path = dir.join(format!("capture-{millis}.png"));
+    let image = RgbaImage::from_raw(256, 256, pixels.to_vec())
+        .ok_or_else(|| anyhow::Error::msg("Pixel buffer is not 256×256 RGBA"))?;
+    image.save(&path)?;
+    Ok(path)
+}
+
+fn draw(pixels: &[u8], camera: Camera, scale: f32) {
     clear_background(BLACK);
+    let size = scale * camera.zoom;
+    let (width, height) = (screen_width(), screen_height());
     for y in 0..256 {
         for x in 0..256 {
-            let t = table[y * 256 + x];
-            draw_rectangle(
-                x as f32 * SCALEF,
-                y as f32 * SCALEF,
-                SCALEF,
-                SCALEF,
-                Color::new(0.0, t, 0.0, 1.0),
-            );
+            let (sx, sy) = camera.to_screen(x as f32 * scale, y as f32 * scale);
+            if sx + size < 0.0 || sy + size < 0.0 || sx > width || sy > height {
+                continue;
+            }
+            let i = (y * 256 + x) * 4;
+            let [r, g, b, a] = [pixels[i], pixels[i + 1], pixels[i + 2], pixels[i + 3]];
+            draw_rectangle(sx, sy, size, size, Color::from_rgba(r, g, b, a));
+        }
+    }
+}
+
+/// Draws one translucent bar per byte value upwards from the bottom edge, unaffected by the camera.
+fn draw_histogram(histogram: &[f32], scale: f32) {
+    let bottom = screen_height();
+    let color = Color::from_rgba(255, 255, 255, 160);
+    for (byte, &value) in histogram.iter().enumerate() {
+        let height = value * HISTOGRAM_HEIGHT * scale;
+        draw_rectangle(byte as f32 * scale, bottom - height, scale, height, color);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn predict_accepts_several_files() {
+        let matches = cli()
+            .try_get_matches_from(["bv", "predict", "model.safetensors", "a", "b", "c"])
+            .unwrap();
+        let (_, args) = matches.subcommand().unwrap();
+        let files: Vec<&PathBuf> = args.get_many::<PathBuf>("FILE").unwrap().collect();
+        assert_eq!(files, [Path::new("a"), Path::new("b"), Path::new("c")]);
+    }
+
+    #[test]
+    fn scale_is_bounded() {
+        for (scale, ok) in [("0", false), ("64", true), ("65", false)] {
+            let matches = cli().try_get_matches_from(["bv", "show", "file", "--scale", scale]);
+            assert_eq!(matches.is_ok(), ok, "scale {scale}");
+        }
+    }
+
+    #[test]
+    fn retry_stops_after_the_last_attempt() {
+        let mut calls = 0;
+        let result: anyhow::Result<()> = retry(3, |_| {
+            calls += 1;
+            Err(anyhow::Error::msg("always fails"))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
+        assert_eq!(retry(3, Ok).unwrap(), 1);
+    }
+
+    #[test]
+    fn dash_reads_all_of_stdin() {
+        let stdin: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+        assert_eq!(read_input_from(Path::new("-"), &stdin[..]).unwrap(), stdin);
+
+        let path = std::env::temp_dir().join(format!("bv-input-{}", std::process::id()));
+        std::fs::write(&path, b"from a file").unwrap();
+        let bytes = read_input_from(&path, &stdin[..]);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(bytes.unwrap(), b"from a file");
+    }
+
+    #[test]
+    fn zoom_keeps_the_cursor_point_in_place() {
+        let camera = Camera::default().zoom_at(2.0, 300.0, 100.0);
+        assert_eq!(camera.zoom, 2.0);
+        assert_eq!(camera.to_screen(300.0, 100.0), (300.0, 100.0));
+        assert_eq!(camera.zoom_at(1000.0, 0.0, 0.0).zoom, MAX_ZOOM);
+        assert_eq!(camera.zoom_at(0.001, 0.0, 0.0).zoom, MIN_ZOOM);
+
+        let panned = camera.pan(20.0, -10.0);
+        assert_eq!(panned.to_screen(300.0, 100.0), (320.0, 90.0));
+    }
+
+    #[test]
+    fn prediction_json_has_the_expected_fields() {
+        let mut probabilities = [0.0; FileType::ALL.len()];
+        probabilities[FileType::Pdf.output() as usize] = 0.75;
+        probabilities[FileType::Zip.output() as usize] = 0.25;
+        let json = prediction_json(
+            Path::new("x.bin"),
+            Some(FileType::Pdf),
+            0.75,
+            &probabilities,
+            &FileType::ALL,
+        );
+        assert_eq!(json["file"], "x.bin");
+        assert_eq!(json["type"], "Pdf");
+        assert_eq!(json["confidence"], 0.75);
+        assert_eq!(json["probabilities"]["Zip"], 0.25);
+        assert_eq!(
+            json["probabilities"].as_object().unwrap().len(),
+            FileType::ALL.len()
+        );
+
+        let json = prediction_json(Path::new("-"), None, 0.2, &probabilities, &FileType::ALL);
+        assert!(json["type"].is_null());
+        assert!(json.get("top").is_none());
+    }
+
+    #[test]
+    fn ranking_json_keeps_the_order() {
+        let json = ranking_json(&[(FileType::Pdf, 0.75), (FileType::Zip, 0.25)]);
+        assert_eq!(json.as_array().unwrap().len(), 2);
+        assert_eq!(json[0]["type"], "Pdf");
+        assert_eq!(json[0]["probability"], 0.75);
+        assert_eq!(json[1]["type"], "Zip");
+    }
+
+    #[test]
+    fn log_level_follows_the_flags() {
+        assert_eq!(level_from_flags(false, false), LevelFilter::Info);
+        assert_eq!(level_from_flags(true, false), LevelFilter::Warn);
+        assert_eq!(level_from_flags(false, true), LevelFilter::Debug);
+    }
+
+    #[test]
+    fn accuracy_is_between_one_and_a_hundred_percent() {
+        for (accuracy, ok) in [("0.5", false), ("1", true), ("100", true), ("100.1", false)] {
+            let matches =
+                cli().try_get_matches_from(["bv", "train", "m", "d", "--accuracy", accuracy]);
+            assert_eq!(matches.is_ok(), ok, "accuracy {accuracy}");
+        }
+        let matches = cli().get_matches_from(["bv", "train", "m", "d", "--accuracy", "42.5"]);
+        let (_, args) = matches.subcommand().unwrap();
+        assert_eq!(args.get_one::<f32>("accuracy"), Some(&42.5));
+    }
+
+    fn show_options() -> ShowOptions {
+        ShowOptions {
+            colormap: Colormap::default(),
+            clip: None,
+            normalize: "log".to_string(),
+            mask_zero: false,
+            histogram: true,
         }
     }
+
+    #[test]
+    fn watcher_reloads_once_the_change_settled() {
+        let path = std::env::temp_dir().join(format!("bv-watch-{}", std::process::id()));
+        std::fs::write(&path, b"before").unwrap();
+        let mut watcher = Watcher::new(path.clone(), show_options());
+        let long_ago = |duration| Instant::now().checked_sub(duration).unwrap();
+
+        std::fs::write(&path, b"after the change").unwrap();
+        let file = File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        watcher.polled_at = long_ago(WATCH_INTERVAL);
+        // The first poll only notices the change and waits for it to settle.
+        assert!(watcher.poll().is_none());
+        assert!(watcher.changed_at.is_some());
+        watcher.polled_at = long_ago(WATCH_INTERVAL);
+        watcher.changed_at = Some(long_ago(WATCH_DEBOUNCE));
+        let frame = watcher.poll();
+        std::fs::remove_file(&path).unwrap();
+
+        let frame = frame.unwrap();
+        let expected = show_options().render(b"after the change");
+        assert_eq!(frame.pixels, expected.pixels);
+        assert_eq!(frame.histogram, expected.histogram);
+        assert!(watcher.changed_at.is_none());
+    }
+
+    #[test]
+    fn capture_writes_the_frame_as_png() {
+        let dir = std::env::temp_dir().join(format!("bv-capture-{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let frame = show_options().render(b"captured frame");
+        let saved = save_capture(&frame.pixels, &dir);
+        let short = save_capture(&frame.pixels[4..], &dir);
+        let loaded = saved
+            .as_ref()
+            .ok()
+            .map(|path| image::open(path).unwrap().to_rgba8().into_raw());
+        std::fs::remove_dir_all(&dir).unwrap();
+        let path = saved.unwrap();
+        assert_eq!(path.extension(), Some("png".as_ref()));
+        assert_eq!(loaded.unwrap(), frame.pixels);
+        assert!(short.is_err());
+    }
 }
@@ -1,30 +1,79 @@
-use std::{fs::DirEntry, path::Path};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-use anyhow::{Error, Result};
-use candle::{DType, Device, Module, Tensor, D};
-use candle_nn::{loss, ops, Linear, Optimizer, VarBuilder, VarMap};
+use candle::{backprop::GradStore, DType, Device, DeviceLocation, Module, Tensor, Var, D};
+use candle_nn::{
+    loss, ops, AdamW, Dropout, Linear, Optimizer as _, ParamsAdamW, VarBuilder, VarMap, SGD,
+};
+use glob::Pattern;
 use log::{info, warn};
-use macroquad::rand::ChooseRandom;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use rayon::prelude::*;
+use safetensors::tensor::Metadata;
 
-use crate::table::BinaryTable;
+use crate::{
+    error::{BvError, ModelFileError, Result},
+    table::{BinaryTable, Normalization},
+};
 
 const N_INPUT: usize = 256 * 256;
 const N_HIDDEN_1: usize = 512;
-const N_OUTPUT: usize = 5;
+pub const N_OUTPUT: usize = 9;
+
+const CLASSES_METADATA_KEY: &str = "classes";
+const CLASS_SCHEME_METADATA_KEY: &str = "class_scheme";
+/// How outputs map to classes: one integer label per class, named by the `classes` metadata.
+/// Change it whenever that encoding changes so older models are refused instead of misread.
+const CLASS_SCHEME: &str = "index:1";
+const NORMALIZATION_METADATA_KEY: &str = "normalization";
+const SAMPLE_BYTES_METADATA_KEY: &str = "sample_bytes";
+const VERSION_METADATA_KEY: &str = "version";
+const ARCHITECTURE_METADATA_KEY: &str = "architecture";
+/// Bump whenever `Network::forward` changes in a way that invalidates saved weights.
+const ARCHITECTURE_REVISION: u32 = 1;
 
 const EPOCHS: usize = 10;
 const LEARNING_RATE: f64 = 0.01;
+const MIN_ACCURACY: f32 = 95.0;
+const SPLIT: f32 = 0.8;
+const EVAL_BATCH_SIZE: usize = 32;
+/// The number of leading bytes inspected by `FileType::from_magic`.
+const MAGIC_LEN: usize = 12;
+/// The number of leading bytes inspected by `FileType::from_content`.
+const CONTENT_SNIFF_LEN: usize = 4096;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum FileType {
     Text,
     Binary,
     Jpeg,
     Pdf,
     Wav,
+    Mp3,
+    Gif,
+    Zip,
+    /// None of the other types, which lets the network abstain.
+    Unknown,
 }
 
 impl FileType {
+    pub const ALL: [Self; N_OUTPUT] = [
+        Self::Text,
+        Self::Binary,
+        Self::Jpeg,
+        Self::Pdf,
+        Self::Wav,
+        Self::Mp3,
+        Self::Gif,
+        Self::Zip,
+        Self::Unknown,
+    ];
+
     pub fn output(self) -> u32 {
         match self {
             Self::Text => 0,
@@ -32,9 +81,98 @@ impl FileType {
             Self::Jpeg => 2,
             Self::Pdf => 3,
             Self::Wav => 4,
+            Self::Mp3 => 5,
+            Self::Gif => 6,
+            Self::Zip => 7,
+            Self::Unknown => 8,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Text => "Text",
+            Self::Binary => "Binary",
+            Self::Jpeg => "Jpeg",
+            Self::Pdf => "Pdf",
+            Self::Wav => "Wav",
+            Self::Mp3 => "Mp3",
+            Self::Gif => "Gif",
+            Self::Zip => "Zip",
+            Self::Unknown => "Unknown",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|typ| typ.name() == name)
+    }
+
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let Some(ext) = path.extension() else {
+            return Some(Self::Binary);
+        };
+        match ext.to_str()? {
+            "txt" | "text" | "TXT" => Some(Self::Text),
+            "bin" | "exe" | "dll" | "so" | "a" => Some(Self::Binary),
+            "jpg" | "jpeg" => Some(Self::Jpeg),
+            "pdf" => Some(Self::Pdf),
+            "wav" => Some(Self::Wav),
+            "mp3" => Some(Self::Mp3),
+            "gif" => Some(Self::Gif),
+            "zip" => Some(Self::Zip),
+            _ => None,
+        }
+    }
+
+    pub fn from_magic(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(b"\xFF\xD8\xFF") {
+            Some(Self::Jpeg)
+        } else if bytes.starts_with(b"%PDF") {
+            Some(Self::Pdf)
+        } else if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WAVE") {
+            Some(Self::Wav)
+        } else if bytes.starts_with(b"ID3") || bytes.starts_with(b"\xFF\xFB") {
+            Some(Self::Mp3)
+        } else if bytes.starts_with(b"GIF8") {
+            Some(Self::Gif)
+        } else if bytes.starts_with(b"PK\x03\x04") {
+            Some(Self::Zip)
+        } else {
+            None
         }
     }
 
+    /// Labels mostly printable UTF-8 as `Text` and everything else as `Binary`.
+    pub fn from_content(bytes: &[u8]) -> Self {
+        let valid = match std::str::from_utf8(bytes) {
+            Ok(_) => true,
+            // A multi-byte character cut off at the end of a sample is fine.
+            Err(err) => err.error_len().is_none(),
+        };
+        let control = bytes
+            .iter()
+            .filter(|&&byte| byte < 0x20 && !matches!(byte, b'\t' | b'\n' | b'\r' | 0x0c))
+            .count();
+        if valid && !bytes.is_empty() && control * 20 < bytes.len() {
+            Self::Text
+        } else {
+            Self::Binary
+        }
+    }
+
+    /// The most likely file type, or `None` if its probability is below `min`. `classes` maps
+    /// output indices to file types, like `Network::classes`.
+    pub fn from_prediction_with_threshold(
+        probs: &[f32],
+        classes: &[FileType],
+        min: f32,
+    ) -> Option<Self> {
+        let (output, confidence) = argmax(probs);
+        if confidence < min {
+            return None;
+        }
+        classes.get(output).copied()
+    }
+
     pub fn from_prediction(output: u32) -> Option<Self> {
         match output {
             0 => Some(Self::Text),
@@ -42,6 +180,10 @@ impl FileType {
             2 => Some(Self::Jpeg),
             3 => Some(Self::Pdf),
             4 => Some(Self::Wav),
+            5 => Some(Self::Mp3),
+            6 => Some(Self::Gif),
+            7 => Some(Self::Zip),
+            8 => Some(Self::Unknown),
             _ => None,
         }
     }
@@ -53,145 +195,1675 @@ pub struct Dataset {
     pub train_outputs: Tensor,
     pub test_inputs: Tensor,
     pub test_outputs: Tensor,
+    /// How the inputs were exported, the trained network applies the same when predicting.
+    pub normalization: Normalization,
+    /// How many files could not be read and were left out.
+    pub unreadable: usize,
+    /// How many leading bytes of every file were parsed, the trained network samples the same.
+    pub sample_bytes: Option<usize>,
+}
+
+/// What `Dataset::collect` would load, based on file extensions alone.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DatasetSummary {
+    /// File counts indexed by `FileType::output`, after applying `max_per_class`.
+    pub counts: [usize; N_OUTPUT],
+    pub skipped: usize,
+    pub bytes: u64,
+    pub train_len: usize,
+    pub test_len: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct DatasetConfig {
+    pub split: f32,
+    pub sniff_magic: bool,
+    pub strict: bool,
+    pub follow_symlinks: bool,
+    /// Keeps at most this many randomly chosen files of every class.
+    pub max_per_class: Option<usize>,
+    /// Seeds the shuffling and sampling of `Dataset::collect` to make it reproducible.
+    pub seed: Option<u64>,
+    /// Extension to file type mappings that take precedence over `FileType::from_path`.
+    pub extensions: HashMap<String, FileType>,
+    /// A directory to store parsed inputs in, keyed by a hash of the file content.
+    pub cache_dir: Option<PathBuf>,
+    pub normalization: Normalization,
+    /// Labels files with unrecognized extensions or signatures as `FileType::Unknown` instead
+    /// of skipping them.
+    pub include_unknown: bool,
+    /// A separate directory to test on. When set, every file of the dataset is trained on and
+    /// `split` is ignored.
+    pub valid_dir: Option<PathBuf>,
+    /// Only parses the first `sample_bytes` bytes of every file.
+    pub sample_bytes: Option<usize>,
+    pub filter: PathFilter,
+    /// Labels files without an extension by their content instead of as `FileType::Binary`.
+    pub sniff_extensionless: bool,
+}
+
+impl DatasetConfig {
+    pub fn file_type(&self, path: &Path) -> Option<FileType> {
+        let custom = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| self.extensions.get(ext));
+        match custom {
+            Some(&file_type) => Some(file_type),
+            None => FileType::from_path(path).or(self.include_unknown.then_some(FileType::Unknown)),
+        }
+    }
+}
+
+/// Reads a JSON object mapping extensions (without the dot) to file type names, e.g.
+/// `{"dat": "Binary", "md": "Text"}`.
+pub fn read_extensions<P>(path: P) -> Result<HashMap<String, FileType>>
+where
+    P: AsRef<Path>,
+{
+    let file = File::open(path)?;
+    let mapping: HashMap<String, String> = serde_json::from_reader(file)
+        .map_err(|err| BvError::InvalidConfig(format!("invalid extension mapping - {err}")))?;
+    mapping
+        .into_iter()
+        .map(|(ext, name)| {
+            let file_type = FileType::from_name(&name)
+                .ok_or_else(|| BvError::InvalidConfig(format!("unknown class '{name}'")))?;
+            Ok((ext.trim_start_matches('.').to_string(), file_type))
+        })
+        .collect()
+}
+
+impl Default for DatasetConfig {
+    fn default() -> Self {
+        Self {
+            split: SPLIT,
+            sniff_magic: false,
+            strict: false,
+            follow_symlinks: true,
+            max_per_class: None,
+            seed: None,
+            extensions: HashMap::new(),
+            cache_dir: None,
+            normalization: Normalization::default(),
+            include_unknown: false,
+            valid_dir: None,
+            sample_bytes: None,
+            filter: PathFilter::default(),
+            sniff_extensionless: false,
+        }
+    }
 }
 
 impl Dataset {
-    pub fn collect<P>(path: P, dev: &Device) -> Result<Self>
+    pub fn collect<P>(path: P, config: &DatasetConfig, dev: &Device) -> Result<Self>
     where
         P: AsRef<Path>,
     {
-        let dir = std::fs::read_dir(path)?;
-        let mut files = Vec::new();
-        let mut table = BinaryTable::new();
-        for sub_dir in dir {
-            read_dir(&mut table, &mut files, &sub_dir?)?;
-        }
-        files.shuffle();
-        let len = files.len();
-        let train_len = (len as f32 * 0.8) as usize;
-        let test_len = len - train_len;
-        if train_len == 0 || test_len == 0 {
-            return Err(Error::msg("Dataset to small"));
-        }
-        let mut train_inputs = Vec::new();
-        let mut train_outputs = Vec::new();
-        let mut test_inputs = Vec::new();
-        let mut test_outputs = Vec::new();
-        for (i, (typ, input)) in files.into_iter().enumerate() {
-            if i < train_len {
-                train_inputs.extend(input);
-                train_outputs.push(typ.output());
-            } else {
-                test_inputs.extend(input);
-                test_outputs.push(typ.output());
+        Self::collect_with_progress(path, config, dev, |_, _| {})
+    }
+
+    /// Like `collect`, but calls `progress(done, total)` after every file has been parsed.
+    pub fn collect_with_progress<P, F>(
+        path: P,
+        config: &DatasetConfig,
+        dev: &Device,
+        progress: F,
+    ) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        F: Fn(usize, usize) + Sync,
+    {
+        if config.valid_dir.is_none() && !(config.split > 0.0 && config.split < 1.0) {
+            return Err(BvError::InvalidConfig(format!(
+                "split {} is not between 0 and 1",
+                config.split
+            )));
+        }
+        let mut rng = seeded_rng(config.seed);
+        let paths = sample_paths(path.as_ref(), config, &mut rng)?;
+        let valid_paths = match &config.valid_dir {
+            Some(valid_dir) => sample_paths(valid_dir, config, &mut rng)?,
+            None => Vec::new(),
+        };
+        if let Some(cache_dir) = &config.cache_dir {
+            std::fs::create_dir_all(cache_dir)?;
+        }
+        let total = paths.len() + valid_paths.len();
+        let done = AtomicUsize::new(0);
+        let unreadable = AtomicUsize::new(0);
+        let read_classes = |paths: &[PathBuf], rng: &mut StdRng| -> Result<Vec<Vec<Vec<f32>>>> {
+            let files = paths
+                .par_iter()
+                .map_init(BinaryTable::new, |table, path| {
+                    let file = match read_file(table, path, config) {
+                        Err(BvError::Io(err)) => {
+                            warn!("Skipping unreadable file {path:?} - {err}");
+                            unreadable.fetch_add(1, Ordering::Relaxed);
+                            Ok(None)
+                        }
+                        file => file,
+                    };
+                    progress(done.fetch_add(1, Ordering::Relaxed) + 1, total);
+                    file
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let mut classes = vec![Vec::new(); N_OUTPUT];
+            for (typ, input) in files.into_iter().flatten() {
+                classes[typ.output() as usize].push(input);
+            }
+            for inputs in classes.iter_mut() {
+                inputs.as_mut_slice().shuffle(rng);
+                if let Some(max) = config.max_per_class {
+                    inputs.truncate(max);
+                }
+            }
+            Ok(classes)
+        };
+        let classes = read_classes(&paths, &mut rng)?;
+        let mut train = Vec::new();
+        let mut test = Vec::new();
+        if config.valid_dir.is_some() {
+            let valid_classes = read_classes(&valid_paths, &mut rng)?;
+            for (output, inputs) in classes.into_iter().enumerate() {
+                train.extend(inputs.into_iter().map(|input| (output as u32, input)));
+            }
+            for (output, inputs) in valid_classes.into_iter().enumerate() {
+                test.extend(inputs.into_iter().map(|input| (output as u32, input)));
+            }
+        } else {
+            for (output, inputs) in classes.into_iter().enumerate() {
+                let train_len = stratum_train_len(inputs.len(), config.split);
+                for (i, input) in inputs.into_iter().enumerate() {
+                    if i < train_len {
+                        train.push((output as u32, input));
+                    } else {
+                        test.push((output as u32, input));
+                    }
+                }
+            }
+        }
+        if train.is_empty() || test.is_empty() {
+            return Err(BvError::DatasetTooSmall);
+        }
+        train.as_mut_slice().shuffle(&mut rng);
+        test.as_mut_slice().shuffle(&mut rng);
+        let (train_inputs, train_outputs) = to_tensors(train, dev)?;
+        let (test_inputs, test_outputs) = to_tensors(test, dev)?;
+        Ok(Self {
+            train_inputs,
+            train_outputs,
+            test_inputs,
+            test_outputs,
+            normalization: config.normalization,
+            unreadable: unreadable.into_inner(),
+            sample_bytes: config.sample_bytes,
+        })
+    }
+
+    /// Walks `path` like `collect` without reading any file. Sniffed magic bytes are not taken
+    /// into account.
+    pub fn summarize<P>(path: P, config: &DatasetConfig) -> Result<DatasetSummary>
+    where
+        P: AsRef<Path>,
+    {
+        if config.valid_dir.is_none() && !(config.split > 0.0 && config.split < 1.0) {
+            return Err(BvError::InvalidConfig(format!(
+                "split {} is not between 0 and 1",
+                config.split
+            )));
+        }
+        let mut summary = DatasetSummary::default();
+        let sizes = class_sizes(path.as_ref(), config, &mut summary.skipped)?;
+        let valid_sizes = match &config.valid_dir {
+            Some(valid_dir) => Some(class_sizes(valid_dir, config, &mut summary.skipped)?),
+            None => None,
+        };
+        for (output, sizes) in sizes.iter().enumerate() {
+            let len = config
+                .max_per_class
+                .map_or(sizes.len(), |max| sizes.len().min(max));
+            summary.bytes += capped_bytes(sizes, len);
+            match &valid_sizes {
+                Some(valid_sizes) => {
+                    let valid = &valid_sizes[output];
+                    let valid_len = config
+                        .max_per_class
+                        .map_or(valid.len(), |max| valid.len().min(max));
+                    summary.counts[output] = len + valid_len;
+                    summary.train_len += len;
+                    summary.test_len += valid_len;
+                    summary.bytes += capped_bytes(valid, valid_len);
+                }
+                None => {
+                    let train_len = stratum_train_len(len, config.split);
+                    summary.counts[output] = len;
+                    summary.train_len += train_len;
+                    summary.test_len += len - train_len;
+                }
             }
         }
-        let train_inputs = Tensor::from_vec(train_inputs, (train_len, N_INPUT), dev)?;
-        let train_outputs = Tensor::from_vec(train_outputs, train_len, dev)?;
-        let test_inputs = Tensor::from_vec(test_inputs, (test_len, N_INPUT), dev)?;
-        let test_outputs = Tensor::from_vec(test_outputs, test_len, dev)?;
+        Ok(summary)
+    }
+
+    /// Like `collect`, but reads the files lazily in batches, for training loops outside this
+    /// crate; `train` itself takes a collected `Dataset`. The files are shuffled with a generator
+    /// seeded with `config.seed`, if any, and not split.
+    pub fn collect_batched<P>(
+        path: P,
+        config: &DatasetConfig,
+        batch_size: usize,
+        dev: &Device,
+    ) -> Result<Batches>
+    where
+        P: AsRef<Path>,
+    {
+        if batch_size == 0 {
+            return Err(BvError::InvalidConfig(
+                "batch size cannot be zero".to_string(),
+            ));
+        }
+        let (files, _) = labeled_files(path, config)?;
+        let mut files: Vec<PathBuf> = files.into_iter().map(|(_, path)| path).collect();
+        files.shuffle(&mut seeded_rng(config.seed));
+        Ok(Batches::new(files, config.clone(), batch_size, dev))
+    }
+
+    /// Weights every class by the inverse of its frequency in the training set.
+    ///
+    /// Classes without training files get a weight of zero.
+    pub fn class_weights(&self) -> Result<Vec<f32>> {
+        let outputs = self.train_outputs.to_vec1::<u32>()?;
+        let mut counts = [0usize; N_OUTPUT];
+        for &output in &outputs {
+            counts[output as usize] += 1;
+        }
+        let weights = counts
+            .iter()
+            .map(|&count| {
+                if count == 0 {
+                    0.0
+                } else {
+                    outputs.len() as f32 / count as f32
+                }
+            })
+            .collect();
+        Ok(weights)
+    }
+
+    /// Splits the same files anew with `rng`, keeping the number of train files of every class.
+    pub fn reshuffle(&self, rng: &mut StdRng) -> Result<Self> {
+        let mut train_counts = [0; N_OUTPUT];
+        for output in self.train_outputs.to_vec1::<u32>()? {
+            train_counts[output as usize] += 1;
+        }
+        let inputs = Tensor::cat(&[&self.train_inputs, &self.test_inputs], 0)?;
+        let outputs = Tensor::cat(&[&self.train_outputs, &self.test_outputs], 0)?;
+        let mut classes = vec![Vec::new(); N_OUTPUT];
+        for (i, output) in outputs.to_vec1::<u32>()?.into_iter().enumerate() {
+            classes[output as usize].push(i as u32);
+        }
+        let mut train = Vec::new();
+        let mut test = Vec::new();
+        for (mut indices, train_len) in classes.into_iter().zip(train_counts) {
+            indices.shuffle(rng);
+            train.extend_from_slice(&indices[..train_len]);
+            test.extend_from_slice(&indices[train_len..]);
+        }
+        train.shuffle(rng);
+        test.shuffle(rng);
+        let select = |indices: Vec<u32>| -> Result<(Tensor, Tensor)> {
+            let len = indices.len();
+            let indices = Tensor::from_vec(indices, len, outputs.device())?;
+            Ok((
+                inputs.index_select(&indices, 0)?,
+                outputs.index_select(&indices, 0)?,
+            ))
+        };
+        let (train_inputs, train_outputs) = select(train)?;
+        let (test_inputs, test_outputs) = select(test)?;
         Ok(Self {
             train_inputs,
             train_outputs,
             test_inputs,
             test_outputs,
+            normalization: self.normalization,
+            unreadable: self.unreadable,
+            sample_bytes: self.sample_bytes,
         })
     }
 }
 
-fn read_dir(
-    table: &mut BinaryTable,
-    files: &mut Vec<(FileType, Vec<f32>)>,
-    entry: &DirEntry,
-) -> Result<()> {
-    let metadata = entry.metadata()?;
-    let path = entry.path();
-    if metadata.is_dir() {
-        let dir = std::fs::read_dir(path)?;
-        for sub_dir in dir {
-            read_dir(table, files, &sub_dir?)?;
+/// Yields shuffled `(inputs, outputs)` batches of the dataset at `path`.
+///
+/// Only the file list is collected up front; each file is read and parsed when its batch is
+/// requested, so peak memory is one batch of `batch_size * N_INPUT` floats instead of the whole
+/// dataset.
+pub struct Batches {
+    files: Vec<PathBuf>,
+    /// How every file is labeled, sampled and normalized, like in `Dataset::collect`.
+    config: DatasetConfig,
+    batch_size: usize,
+    position: usize,
+    table: BinaryTable,
+    dev: Device,
+    /// The number of files skipped so far because reading them failed.
+    pub unreadable: usize,
+}
+
+impl Batches {
+    fn new(files: Vec<PathBuf>, config: DatasetConfig, batch_size: usize, dev: &Device) -> Self {
+        Self {
+            files,
+            config,
+            batch_size,
+            position: 0,
+            table: BinaryTable::new(),
+            dev: dev.clone(),
+            unreadable: 0,
+        }
+    }
+}
+
+impl Iterator for Batches {
+    type Item = Result<(Tensor, Tensor)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.position >= self.files.len() {
+            return None;
+        }
+        let end = (self.position + self.batch_size).min(self.files.len());
+        let mut rows = Vec::with_capacity(end - self.position);
+        for path in &self.files[self.position..end] {
+            match read_file(&mut self.table, path, &self.config) {
+                Ok(Some((file_type, input))) => rows.push((file_type.output(), input)),
+                Ok(None) => {}
+                Err(BvError::Io(err)) => {
+                    warn!("Skipping unreadable file {path:?} - {err}");
+                    self.unreadable += 1;
+                }
+                Err(err) => return Some(Err(err)),
+            }
+        }
+        self.position = end;
+        if rows.is_empty() {
+            return self.next();
+        }
+        Some(to_tensors(rows, &self.dev))
+    }
+}
+
+/// Collects every file below `path` with a known extension, along with the number of skipped files.
+fn labeled_files<P>(path: P, config: &DatasetConfig) -> Result<(Vec<(FileType, PathBuf)>, usize)>
+where
+    P: AsRef<Path>,
+{
+    let mut files = Vec::new();
+    let mut skipped = 0;
+    walk_dir(path, config.follow_symlinks, &config.filter, &mut |path| {
+        match config.file_type(&path) {
+            Some(file_type) => files.push((file_type, path)),
+            None => {
+                warn!("Ignoring file with unknown extension {path:?}");
+                skipped += 1;
+            }
         }
+        Ok(())
+    })?;
+    Ok((files, skipped))
+}
+
+/// Walks `path` and keeps at most `max_per_class` randomly chosen files of every class.
+fn sample_paths(path: &Path, config: &DatasetConfig, rng: &mut StdRng) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    walk_dir(path, config.follow_symlinks, &config.filter, &mut |path| {
+        paths.push(path);
+        Ok(())
+    })?;
+    paths.sort();
+    if let Some(max) = config.max_per_class {
+        paths.as_mut_slice().shuffle(rng);
+        let mut counts = HashMap::new();
+        paths.retain(|path| match config.file_type(path) {
+            Some(file_type) => {
+                let count = counts.entry(file_type).or_insert(0);
+                *count += 1;
+                *count <= max
+            }
+            None => true,
+        });
+    }
+    Ok(paths)
+}
+
+/// The file sizes under `path` indexed by `FileType::output`.
+fn class_sizes(path: &Path, config: &DatasetConfig, skipped: &mut usize) -> Result<Vec<Vec<u64>>> {
+    let (files, skipped_here) = labeled_files(path, config)?;
+    *skipped += skipped_here;
+    let mut sizes = vec![Vec::new(); N_OUTPUT];
+    for (file_type, path) in files {
+        sizes[file_type.output() as usize].push(std::fs::metadata(path)?.len());
+    }
+    Ok(sizes)
+}
+
+/// With a cap the loaded files are random, so this estimates their size from the average.
+fn capped_bytes(sizes: &[u64], len: usize) -> u64 {
+    if sizes.is_empty() {
+        return 0;
+    }
+    let total: u64 = sizes.iter().sum();
+    total * len as u64 / sizes.len() as u64
+}
+
+/// Every class with at least two files keeps at least one file on each side of the split.
+fn stratum_train_len(len: usize, split: f32) -> usize {
+    if len < 2 {
+        return len;
+    }
+    ((len as f32 * split) as usize).clamp(1, len - 1)
+}
+
+fn to_tensors(rows: Vec<(u32, Vec<f32>)>, dev: &Device) -> Result<(Tensor, Tensor)> {
+    let len = rows.len();
+    let input_len = rows.first().map_or(0, |(_, input)| input.len());
+    let mut inputs = Vec::with_capacity(len * input_len);
+    let mut outputs = Vec::with_capacity(len);
+    for (output, input) in rows {
+        inputs.extend(input);
+        outputs.push(output);
+    }
+    let inputs = Tensor::from_vec(inputs, (len, input_len), dev)?;
+    let outputs = Tensor::from_vec(outputs, len, dev)?;
+    Ok((inputs, outputs))
+}
+
+/// Which files `walk_dir` reports. Patterns are matched against the path relative to the walked
+/// directory, e.g. `**/.git/**`.
+#[derive(Clone, Debug, Default)]
+pub struct PathFilter {
+    /// When not empty, only files matching one of these patterns are reported.
+    pub include: Vec<Pattern>,
+    pub exclude: Vec<Pattern>,
+    /// Also enters hidden directories and reports hidden files, whose names start with a dot.
+    pub hidden: bool,
+}
+
+impl PathFilter {
+    fn allows(&self, relative: &Path) -> bool {
+        let matches = |pattern: &Pattern| pattern.matches_path(relative);
+        (self.include.is_empty() || self.include.iter().any(matches))
+            && !self.exclude.iter().any(matches)
+    }
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with('.'))
+}
+
+/// Calls `f` for every file below `path` that passes `filter`.
+///
+/// Symlinks are resolved when `follow_symlinks` is set and skipped otherwise. Every directory is
+/// entered at most once, so symlink cycles terminate.
+pub fn walk_dir<P, F>(path: P, follow_symlinks: bool, filter: &PathFilter, f: &mut F) -> Result<()>
+where
+    P: AsRef<Path>,
+    F: FnMut(PathBuf) -> Result<()>,
+{
+    let mut visited = HashSet::new();
+    let root = path.as_ref();
+    walk(root, root, follow_symlinks, filter, &mut visited, f)
+}
+
+fn walk<F>(
+    root: &Path,
+    path: &Path,
+    follow_symlinks: bool,
+    filter: &PathFilter,
+    visited: &mut HashSet<DirKey>,
+    f: &mut F,
+) -> Result<()>
+where
+    F: FnMut(PathBuf) -> Result<()>,
+{
+    if !visited.insert(dir_key(path)?) {
         return Ok(());
     }
-    let ext = path.extension().map(|s| s.to_str().expect("Fuck Windows"));
-    let file_type = match ext {
-        Some("txt" | "text" | "TXT") => FileType::Text,
-        None | Some("bin" | "exe" | "dll" | "so" | "a") => FileType::Binary,
-        Some("jpg" | "jpeg") => FileType::Jpeg,
-        Some("pdf") => FileType::Pdf,
-        Some("wav") => FileType::Wav,
-        _ => {
-            warn!("Ignoring file with unknown extension {path:?}");
-            return Ok(());
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let path = entry.path();
+        if !filter.hidden && is_hidden(&path) {
+            continue;
+        }
+        let mut file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            if !follow_symlinks {
+                continue;
+            }
+            match std::fs::metadata(&path) {
+                Ok(metadata) => file_type = metadata.file_type(),
+                Err(err) => {
+                    warn!("Ignoring broken symlink {path:?} - {err}");
+                    continue;
+                }
+            }
+        }
+        if file_type.is_dir() {
+            walk(root, &path, follow_symlinks, filter, visited, f)?;
+        } else if filter.allows(path.strip_prefix(root).unwrap_or(&path)) {
+            f(path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+type DirKey = (u64, u64);
+
+#[cfg(unix)]
+fn dir_key(path: &Path) -> Result<DirKey> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = std::fs::metadata(path)?;
+    Ok((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+type DirKey = PathBuf;
+
+#[cfg(not(unix))]
+fn dir_key(path: &Path) -> Result<DirKey> {
+    Ok(path.canonicalize()?)
+}
+
+fn read_file(
+    table: &mut BinaryTable,
+    path: &Path,
+    config: &DatasetConfig,
+) -> Result<Option<(FileType, Vec<f32>)>> {
+    let Some(mut file_type) = config.file_type(path) else {
+        warn!("Ignoring file with unknown extension {path:?}");
+        return Ok(None);
+    };
+    let sniff_content = config.sniff_extensionless && path.extension().is_none();
+    let header_len = if sniff_content {
+        CONTENT_SNIFF_LEN
+    } else {
+        MAGIC_LEN
+    };
+    let mut file = File::open(path)?;
+    let mut header = Vec::with_capacity(header_len);
+    (&mut file)
+        .take(header_len as u64)
+        .read_to_end(&mut header)?;
+    if sniff_content {
+        file_type = FileType::from_content(&header);
+    }
+    if config.sniff_magic {
+        if has_foreign_magic(&header) {
+            if !config.include_unknown {
+                warn!("Ignoring file with unsupported signature {path:?}");
+                return Ok(None);
+            }
+            file_type = FileType::Unknown;
+        }
+        match FileType::from_magic(&header) {
+            Some(detected) if detected != file_type && config.strict => {
+                warn!("Ignoring {path:?}, its extension says {file_type:?} but its content is {detected:?}");
+                return Ok(None);
+            }
+            Some(detected) if detected != file_type => {
+                warn!("Relabeling {path:?} from {file_type:?} to {detected:?}");
+                file_type = detected;
+            }
+            _ => {}
         }
+    }
+    let limit = sample_limit(config.sample_bytes);
+    let sampled = &header[..header.len().min(limit as usize)];
+    let rest = file.take(limit.saturating_sub(sampled.len() as u64));
+    let Some(cache_dir) = &config.cache_dir else {
+        let parsed = table.parse_reader(sampled.chain(rest));
+        let input = table.export_with(config.normalization);
+        table.clear();
+        parsed?;
+        return Ok(Some((file_type, input)));
+    };
+    let mut reader = HashingReader {
+        inner: sampled.chain(rest),
+        hasher: blake3::Hasher::new(),
     };
-    let bytes = std::fs::read(path)?;
-    table.parse(&bytes);
-    let input = table.export();
+    let parsed = table.parse_reader(&mut reader);
+    if let Err(err) = parsed {
+        table.clear();
+        return Err(err.into());
+    }
+    let cache_path = cache_dir.join(format!(
+        "{}-{}.f32",
+        reader.hasher.finalize().to_hex(),
+        config.normalization.name()
+    ));
+    if let Some(input) = read_cached(&cache_path) {
+        table.clear();
+        return Ok(Some((file_type, input)));
+    }
+    let input = table.export_with(config.normalization);
     table.clear();
-    files.push((file_type, input));
+    // The input is computed either way, a broken cache must not drop files from the dataset.
+    if let Err(err) = write_cached(&cache_path, &input) {
+        warn!("Could not cache {path:?} in {cache_path:?} - {err}");
+    }
+    Ok(Some((file_type, input)))
+}
+
+/// Hashes everything read through it, so a file is hashed while it is parsed instead of being
+/// buffered first.
+struct HashingReader<R> {
+    inner: R,
+    hasher: blake3::Hasher,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// The number of bytes to read from every file, for use with `Read::take`.
+pub fn sample_limit(sample_bytes: Option<usize>) -> u64 {
+    sample_bytes.map_or(u64::MAX, |sample_bytes| sample_bytes as u64)
+}
+
+fn read_cached(path: &Path) -> Option<Vec<f32>> {
+    let bytes = std::fs::read(path).ok()?;
+    if bytes.len() != N_INPUT * 4 {
+        return None;
+    }
+    let input = bytes
+        .chunks_exact(4)
+        .map(|chunk| f32::from_le_bytes(chunk.try_into().expect("Chunks of 4 bytes")))
+        .collect();
+    Some(input)
+}
+
+/// Writes through a temporary file so concurrent readers never see a partial entry. The
+/// temporary name is unique per process and thread, as several runs may share a cache.
+fn write_cached(path: &Path, input: &[f32]) -> Result<()> {
+    let bytes: Vec<u8> = input.iter().flat_map(|t| t.to_le_bytes()).collect();
+    let thread = rayon::current_thread_index().unwrap_or(0);
+    let tmp = path.with_extension(format!("tmp{}-{thread}", std::process::id()));
+    std::fs::write(&tmp, bytes)?;
+    std::fs::rename(tmp, path)?;
     Ok(())
 }
 
+/// Signatures of well-known formats that none of the file types cover.
+fn has_foreign_magic(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"\x89PNG") || bytes.starts_with(b"OggS")
+}
+
+/// The inverse of `parse_device`, e.g. `cuda:1`.
+pub fn device_name(dev: &Device) -> String {
+    match dev.location() {
+        DeviceLocation::Cpu => "cpu".to_string(),
+        DeviceLocation::Cuda { gpu_id } => format!("cuda:{gpu_id}"),
+    }
+}
+
+pub fn parse_device(spec: &str) -> Result<Device> {
+    match spec {
+        "cpu" => Ok(Device::Cpu),
+        "cuda" => Ok(Device::new_cuda(0)?),
+        "metal" => Err(BvError::UnsupportedDevice(
+            "Metal is not supported by this build".to_string(),
+        )),
+        _ => {
+            let Some(ordinal) = spec.strip_prefix("cuda:") else {
+                return Err(BvError::UnsupportedDevice(format!(
+                    "unknown device '{spec}'"
+                )));
+            };
+            let ordinal = ordinal.parse().map_err(|_| {
+                BvError::UnsupportedDevice(format!("invalid CUDA ordinal in '{spec}'"))
+            })?;
+            Ok(Device::new_cuda(ordinal)?)
+        }
+    }
+}
+
+pub fn argmax(values: &[f32]) -> (usize, f32) {
+    values
+        .iter()
+        .copied()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .expect("Values are not empty")
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct NetworkConfig {
+    pub input: usize,
+    pub hidden: Vec<usize>,
+    pub output: usize,
+    /// Dropout probability after every hidden layer, only applied while training.
+    pub dropout: f32,
+    pub normalization: Normalization,
+    /// Only the first `sample_bytes` bytes of an input are parsed, see `Network::sample`.
+    pub sample_bytes: Option<usize>,
+}
+
+impl NetworkConfig {
+    const METADATA_KEY: &'static str = "layers";
+
+    pub fn sizes(&self) -> Vec<usize> {
+        let mut sizes = Vec::with_capacity(self.hidden.len() + 2);
+        sizes.push(self.input);
+        sizes.extend(&self.hidden);
+        sizes.push(self.output);
+        sizes
+    }
+
+    fn encode(&self) -> String {
+        let sizes: Vec<String> = self.sizes().iter().map(ToString::to_string).collect();
+        sizes.join(",")
+    }
+
+    /// A stable hash of everything that determines the shape and meaning of the weights.
+    fn architecture(&self) -> String {
+        let description = format!("{ARCHITECTURE_REVISION}:{}", self.encode());
+        let hash = description
+            .bytes()
+            .fold(0xcbf29ce484222325u64, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+            });
+        format!("{hash:016x}")
+    }
+
+    fn decode(encoded: &str) -> Result<Self> {
+        let sizes = encoded
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<Vec<usize>, _>>()
+            .map_err(|_| BvError::InvalidModel(format!("invalid layer sizes '{encoded}'")))?;
+        let [input, hidden @ .., output] = sizes.as_slice() else {
+            return Err(BvError::InvalidModel(format!(
+                "invalid layer sizes '{encoded}'"
+            )));
+        };
+        Ok(Self {
+            input: *input,
+            hidden: hidden.to_vec(),
+            output: *output,
+            dropout: 0.0,
+            normalization: Normalization::default(),
+            sample_bytes: None,
+        })
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            input: N_INPUT,
+            hidden: vec![N_HIDDEN_1],
+            output: N_OUTPUT,
+            dropout: 0.0,
+            normalization: Normalization::default(),
+            sample_bytes: None,
+        }
+    }
+}
+
 pub struct Network {
-    pub ln1: Linear,
-    pub ln2: Linear,
+    pub config: NetworkConfig,
+    pub layers: Vec<Linear>,
+    pub dropout: Dropout,
+    /// The file type of every output index, in the order the network was trained with.
+    pub classes: Vec<FileType>,
+    /// The precision of the weights, inputs are cast to it and logits are always `F32`.
+    pub dtype: DType,
 }
 
 impl Network {
-    pub fn new(vs: VarBuilder) -> Result<Self> {
-        let ln1 = candle_nn::linear(N_INPUT, N_HIDDEN_1, vs.pp("ln1"))?;
-        let ln2 = candle_nn::linear(N_HIDDEN_1, N_OUTPUT + 1, vs.pp("ln2"))?;
-        Ok(Self { ln1, ln2 })
+    pub fn new(vs: VarBuilder, config: &NetworkConfig) -> Result<Self> {
+        if config.output != N_OUTPUT {
+            return Err(BvError::InvalidModel(format!(
+                "output layer has {} units, expected one per class ({N_OUTPUT})",
+                config.output
+            )));
+        }
+        let sizes = config.sizes();
+        let mut layers = Vec::with_capacity(sizes.len() - 1);
+        for (i, pair) in sizes.windows(2).enumerate() {
+            let layer = candle_nn::linear(pair[0], pair[1], vs.pp(format!("ln{}", i + 1)))?;
+            layers.push(layer);
+        }
+        Ok(Self {
+            config: config.clone(),
+            layers,
+            dropout: Dropout::new(config.dropout),
+            classes: FileType::ALL.to_vec(),
+            dtype: vs.dtype(),
+        })
+    }
+
+    /// Loads the network at `path` and converts its weights to `dtype`, see `Network::to_dtype`.
+    pub fn load_as<P>(path: P, dev: &Device, dtype: DType) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::load(path, dev)?.to_dtype(dtype, dev)
+    }
+
+    /// Converts the weights to `dtype`, e.g. `F16` to halve the memory used for inference. Fails
+    /// if `dev` cannot run the network in that precision.
+    pub fn to_dtype(mut self, dtype: DType, dev: &Device) -> Result<Self> {
+        if !matches!(dtype, DType::F16 | DType::BF16 | DType::F32 | DType::F64) {
+            return Err(BvError::InvalidConfig(format!(
+                "{} is not a floating point type",
+                dtype.as_str()
+            )));
+        }
+        let convert = |layer: &Linear| -> candle::Result<Linear> {
+            let weight = layer.weight().to_dtype(dtype)?;
+            let bias = layer.bias().map(|bias| bias.to_dtype(dtype)).transpose()?;
+            Ok(Linear::new(weight, bias))
+        };
+        self.layers = self
+            .layers
+            .iter()
+            .map(convert)
+            .collect::<candle::Result<_>>()?;
+        self.dtype = dtype;
+        let probe = Tensor::zeros((1, self.config.input), DType::F32, dev)?;
+        if let Err(err) = self.forward(&probe, false) {
+            return Err(BvError::UnsupportedDevice(format!(
+                "{} is not supported on {} - {err}",
+                dtype.as_str(),
+                device_name(dev)
+            )));
+        }
+        Ok(self)
     }
 
     pub fn load<P>(path: P, dev: &Device) -> Result<Self>
     where
         P: AsRef<Path>,
     {
+        let path = path.as_ref();
+        let stored = decode_metadata(&read_metadata(path)?)?;
+        let config = &stored.config;
+        if let Some(found) = &stored.architecture {
+            let expected = config.architecture();
+            if *found != expected {
+                return Err(BvError::ModelVersionMismatch {
+                    version: stored.version.unwrap_or_else(|| "unknown".to_string()),
+                    found: found.clone(),
+                    expected,
+                });
+            }
+        }
         let mut varmap = VarMap::new();
         let vs = VarBuilder::from_varmap(&varmap, DType::F32, dev);
-        let result = Self::new(vs.clone())?;
-        varmap.load(path)?;
+        let mut result = Self::new(vs.clone(), config)?;
+        match &stored.class_scheme {
+            Some(scheme) if scheme != CLASS_SCHEME => {
+                return Err(BvError::InvalidModel(format!(
+                    "classes are encoded with scheme '{scheme}' but this version reads \
+                     '{CLASS_SCHEME}', retrain the model with this version"
+                )));
+            }
+            None if stored.classes.is_none() => {
+                return Err(BvError::InvalidModel(
+                    "model does not record its classes, retrain it with this version".to_string(),
+                ));
+            }
+            _ => {}
+        }
+        if let Some(classes) = &stored.classes {
+            result.classes = classes
+                .iter()
+                .map(|name| {
+                    FileType::from_name(name)
+                        .ok_or_else(|| BvError::InvalidModel(format!("unknown class '{name}'")))
+                })
+                .collect::<Result<_>>()?;
+        }
+        varmap.load(path).map_err(|err| BvError::InvalidModelFile {
+            path: path.to_path_buf(),
+            source: ModelFileError::Parse(err.to_string()),
+        })?;
         Ok(result)
     }
 
-    pub fn forward(&self, xs: &Tensor) -> Result<Tensor> {
-        let xs = self.ln1.forward(xs)?;
-        let xs = xs.relu()?;
-        self.ln2.forward(&xs).map_err(Into::into)
+    pub fn save<P>(&self, varmap: &VarMap, path: P) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let mut metadata = HashMap::new();
+        metadata.insert(
+            VERSION_METADATA_KEY.to_string(),
+            env!("CARGO_PKG_VERSION").to_string(),
+        );
+        metadata.insert(
+            ARCHITECTURE_METADATA_KEY.to_string(),
+            self.config.architecture(),
+        );
+        metadata.insert(
+            NetworkConfig::METADATA_KEY.to_string(),
+            self.config.encode(),
+        );
+        metadata.insert(
+            NORMALIZATION_METADATA_KEY.to_string(),
+            self.config.normalization.name().to_string(),
+        );
+        if let Some(sample_bytes) = self.config.sample_bytes {
+            metadata.insert(
+                SAMPLE_BYTES_METADATA_KEY.to_string(),
+                sample_bytes.to_string(),
+            );
+        }
+        let classes: Vec<&str> = self.classes.iter().map(|typ| typ.name()).collect();
+        metadata.insert(CLASSES_METADATA_KEY.to_string(), classes.join(","));
+        metadata.insert(
+            CLASS_SCHEME_METADATA_KEY.to_string(),
+            CLASS_SCHEME.to_string(),
+        );
+        let vars = varmap.data().lock().expect("VarMap lock poisoned");
+        let tensors = vars.iter().map(|(name, var)| (name, var.as_tensor()));
+        safetensors::tensor::serialize_to_file(tensors, &Some(metadata), path.as_ref())?;
+        Ok(())
+    }
+
+    /// The part of `bytes` the network was trained to look at.
+    pub fn sample<'a>(&self, bytes: &'a [u8]) -> &'a [u8] {
+        match self.config.sample_bytes {
+            Some(sample_bytes) => &bytes[..bytes.len().min(sample_bytes)],
+            None => bytes,
+        }
+    }
+
+    pub fn file_type(&self, output: usize) -> Option<FileType> {
+        self.classes.get(output).copied()
+    }
+
+    /// Inputs have to be a `(batch, input)` matrix.
+    fn check_input(&self, xs: &Tensor) -> Result<()> {
+        match xs.dims() {
+            &[_, input] if input == self.config.input => Ok(()),
+            dims => Err(BvError::ShapeMismatch {
+                expected: format!("(batch, {})", self.config.input),
+                got: dims.to_vec(),
+            }),
+        }
+    }
+
+    pub fn forward(&self, xs: &Tensor, train: bool) -> Result<Tensor> {
+        self.check_input(xs)?;
+        let (last, hidden) = self.layers.split_last().expect("Network has no layers");
+        let mut xs = xs.to_dtype(self.dtype)?;
+        for layer in hidden {
+            xs = layer.forward(&xs)?.relu()?;
+            xs = self.dropout.forward(&xs, train)?;
+        }
+        Ok(last.forward(&xs)?.to_dtype(DType::F32)?)
     }
 
     pub fn predict(&self, table: &BinaryTable, dev: &Device) -> Result<u32> {
-        let input = table.export();
-        let input = Tensor::from_vec(input, (1, N_INPUT), dev)?;
-        let result = self.forward(&input)?;
-        let result = result.argmax(D::Minus1)?.to_dtype(DType::F32)?.get(0)?;
-        let output = result.get(0)?.to_dtype(DType::U32)?.to_scalar::<u32>()?;
-        Ok(output)
+        let probabilities = self.predict_probabilities(table, dev)?;
+        let (output, _) = argmax(&probabilities);
+        Ok(output as u32)
     }
-}
 
-pub fn train<P>(m: Dataset, path: P, dev: &Device) -> Result<Network>
-where
-    P: AsRef<Path>,
-{
-    let train_inputs = m.train_inputs.to_device(dev)?;
-    let train_outputs = m.train_outputs.to_device(dev)?;
-    let mut varmap = VarMap::new();
-    let vs = VarBuilder::from_varmap(&varmap, DType::F32, dev);
-    let model = Network::new(vs.clone())?;
-    _ = varmap.load(&path);
-    let mut sgd = candle_nn::SGD::new(varmap.all_vars(), LEARNING_RATE)?;
-    let test_inputs = m.test_inputs.to_device(dev)?;
+    /// Predicts the output index of every row of a `(batch, N_INPUT)` tensor of exported tables.
+    pub fn predict_batch(&self, inputs: &Tensor, dev: &Device) -> Result<Vec<u32>> {
+        self.check_input(inputs)?;
+        let inputs = inputs.to_device(dev)?;
+        let logits = self.forward(&inputs, false)?;
+        Ok(logits.argmax(D::Minus1)?.to_vec1::<u32>()?)
+    }
+
+    /// Classifies every `window`-byte slice of `bytes`, starting every `step` bytes, and returns
+    /// `(offset, file type, confidence)` for each. Inputs shorter than `window` are classified
+    /// as a whole.
+    pub fn scan(
+        &self,
+        bytes: &[u8],
+        window: usize,
+        step: usize,
+        dev: &Device,
+    ) -> Result<Vec<(usize, FileType, f32)>> {
+        if window < 2 || step == 0 {
+            return Err(BvError::InvalidConfig(format!(
+                "window {window} must be at least 2 and step {step} positive"
+            )));
+        }
+        let mut table = BinaryTable::new();
+        let mut results = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let end = (offset + window).min(bytes.len());
+            table.clear();
+            table.parse(&bytes[offset..end]);
+            if table.is_empty() {
+                break;
+            }
+            let probabilities = self.predict_probabilities(&table, dev)?;
+            let (output, confidence) = argmax(&probabilities);
+            if let Some(file_type) = self.file_type(output) {
+                results.push((offset, file_type, confidence));
+            }
+            if end == bytes.len() {
+                break;
+            }
+            offset += step;
+        }
+        Ok(results)
+    }
+
+    /// Returns the `k` most likely file types sorted by descending probability. `k` is clamped
+    /// to the number of classes.
+    pub fn predict_topk(
+        &self,
+        table: &BinaryTable,
+        dev: &Device,
+        k: usize,
+    ) -> Result<Vec<(FileType, f32)>> {
+        let probabilities = self.predict_probabilities(table, dev)?;
+        Ok(self.rank(probabilities, k))
+    }
+
+    /// The `k` most likely file types, most likely first.
+    fn rank(&self, probabilities: [f32; N_OUTPUT], k: usize) -> Vec<(FileType, f32)> {
+        let mut ranked = probabilities.into_iter().enumerate().collect::<Vec<_>>();
+        ranked.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        ranked
+            .into_iter()
+            .filter_map(|(output, p)| Some((self.file_type(output)?, p)))
+            .take(k.min(N_OUTPUT))
+            .collect()
+    }
+
+    pub fn predict_probabilities(
+        &self,
+        table: &BinaryTable,
+        dev: &Device,
+    ) -> Result<[f32; N_OUTPUT]> {
+        if table.is_empty() {
+            return Err(BvError::InputTooShort);
+        }
+        let input = table.export_with(self.config.normalization);
+        if input.len() != self.config.input {
+            return Err(BvError::ShapeMismatch {
+                expected: format!("(1, {})", self.config.input),
+                got: vec![1, input.len()],
+            });
+        }
+        let input = Tensor::from_vec(input, (1, self.config.input), dev)?;
+        let logits = self.forward(&input, false)?;
+        let (batch, classes) = logits.dims2()?;
+        if batch != 1 || classes != N_OUTPUT {
+            return Err(BvError::InvalidModel(format!(
+                "unexpected logits shape ({batch}, {classes}), expected (1, {N_OUTPUT})"
+            )));
+        }
+        let probabilities = ops::softmax(&logits, D::Minus1)?.get(0)?.to_vec1::<f32>()?;
+        let mut output = [0.0; N_OUTPUT];
+        output.copy_from_slice(&probabilities);
+        Ok(output)
+    }
+}
+
+/// What a saved model records about itself, read from its header without loading the weights.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModelInfo {
+    /// The crate version that saved the model.
+    pub version: Option<String>,
+    pub architecture: Option<String>,
+    pub layers: Vec<usize>,
+    /// The class names in output order, as stored without checking they are known.
+    pub classes: Option<Vec<String>>,
+    pub class_scheme: Option<String>,
+    pub normalization: Normalization,
+    pub sample_bytes: Option<usize>,
+    /// The number of weights and biases.
+    pub parameters: usize,
+}
+
+impl ModelInfo {
+    pub fn read<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let header = read_header(path.as_ref())?;
+        let stored = decode_metadata(&header.metadata().clone().unwrap_or_default())?;
+        let parameters = header
+            .tensors()
+            .values()
+            .map(|info| info.shape.iter().product::<usize>())
+            .sum();
+        Ok(Self {
+            version: stored.version,
+            architecture: stored.architecture,
+            layers: stored.config.sizes(),
+            classes: stored.classes,
+            class_scheme: stored.class_scheme,
+            normalization: stored.config.normalization,
+            sample_bytes: stored.config.sample_bytes,
+            parameters,
+        })
+    }
+}
+
+/// Averages the probabilities of several networks, e.g. trained with different seeds.
+pub struct Ensemble {
+    pub models: Vec<Network>,
+}
+
+impl Ensemble {
+    /// Loads every model, which all have to agree on their layers, classes and how they read
+    /// their inputs.
+    pub fn load<P>(paths: &[P], dev: &Device) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let models = paths
+            .iter()
+            .map(|path| Network::load(path, dev))
+            .collect::<Result<Vec<_>>>()?;
+        let Some((first, rest)) = models.split_first() else {
+            return Err(BvError::InvalidConfig(
+                "an ensemble needs at least one model".to_string(),
+            ));
+        };
+        for model in rest {
+            let config = &model.config;
+            if config.sizes() != first.config.sizes()
+                || config.normalization != first.config.normalization
+                || config.sample_bytes != first.config.sample_bytes
+                || model.classes != first.classes
+            {
+                return Err(BvError::InvalidModel(
+                    "ensemble members differ in their layers, classes or inputs".to_string(),
+                ));
+            }
+        }
+        Ok(Self { models })
+    }
+
+    /// Converts the weights of every model, see `Network::to_dtype`.
+    pub fn to_dtype(self, dtype: DType, dev: &Device) -> Result<Self> {
+        let models = self
+            .models
+            .into_iter()
+            .map(|model| model.to_dtype(dtype, dev))
+            .collect::<Result<_>>()?;
+        Ok(Self { models })
+    }
+
+    fn first(&self) -> &Network {
+        self.models.first().expect("Ensemble has no models")
+    }
+
+    pub fn sample<'a>(&self, bytes: &'a [u8]) -> &'a [u8] {
+        self.first().sample(bytes)
+    }
+
+    pub fn classes(&self) -> &[FileType] {
+        &self.first().classes
+    }
+
+    pub fn file_type(&self, output: usize) -> Option<FileType> {
+        self.first().file_type(output)
+    }
+
+    /// The mean of the probabilities predicted by every model.
+    pub fn predict(&self, table: &BinaryTable, dev: &Device) -> Result<[f32; N_OUTPUT]> {
+        let mut sum = [0.0; N_OUTPUT];
+        for model in &self.models {
+            let probabilities = model.predict_probabilities(table, dev)?;
+            for (sum, p) in sum.iter_mut().zip(probabilities) {
+                *sum += p;
+            }
+        }
+        let len = self.models.len() as f32;
+        Ok(sum.map(|sum| sum / len))
+    }
+
+    pub fn predict_topk(
+        &self,
+        table: &BinaryTable,
+        dev: &Device,
+        k: usize,
+    ) -> Result<Vec<(FileType, f32)>> {
+        let probabilities = self.predict(table, dev)?;
+        Ok(self.first().rank(probabilities, k))
+    }
+}
+
+/// Loads the weights saved at `path` into `varmap` once their architecture matches `config` and
+/// returns the number of restored parameters.
+fn restore_weights(varmap: &mut VarMap, path: &Path, config: &NetworkConfig) -> Result<usize> {
+    let stored = decode_metadata(&read_metadata(path)?)?;
+    let found = stored
+        .architecture
+        .unwrap_or_else(|| stored.config.architecture());
+    if found != config.architecture() {
+        return Err(BvError::InvalidConfig(format!(
+            "{} has layers {} but training expects {}",
+            path.display(),
+            stored.config.encode(),
+            config.encode()
+        )));
+    }
+    if stored.config.normalization != config.normalization {
+        warn!(
+            "{} was trained on {} normalized inputs, continuing with {}",
+            path.display(),
+            stored.config.normalization.name(),
+            config.normalization.name()
+        );
+    }
+    varmap.load(path).map_err(|err| BvError::InvalidModelFile {
+        path: path.to_path_buf(),
+        source: ModelFileError::Parse(err.to_string()),
+    })?;
+    Ok(varmap.all_vars().iter().map(|var| var.elem_count()).sum())
+}
+
+/// The metadata a model was saved with, see `Network::save`.
+struct StoredMetadata {
+    /// The crate version that saved the model.
+    version: Option<String>,
+    architecture: Option<String>,
+    /// The layer sizes, normalization and sample size, with the defaults of models saved
+    /// before they were recorded.
+    config: NetworkConfig,
+    /// The class names in output order, not checked to be known.
+    classes: Option<Vec<String>>,
+    class_scheme: Option<String>,
+}
+
+/// Decodes everything `Network::save` records, for loading, resuming and `ModelInfo` alike.
+fn decode_metadata(metadata: &HashMap<String, String>) -> Result<StoredMetadata> {
+    let mut config = match metadata.get(NetworkConfig::METADATA_KEY) {
+        Some(encoded) => NetworkConfig::decode(encoded)?,
+        None => NetworkConfig::default(),
+    };
+    if let Some(normalization) = metadata.get(NORMALIZATION_METADATA_KEY) {
+        config.normalization = normalization.parse()?;
+    }
+    if let Some(sample_bytes) = metadata.get(SAMPLE_BYTES_METADATA_KEY) {
+        config.sample_bytes =
+            Some(sample_bytes.parse().map_err(|_| {
+                BvError::InvalidModel(format!("invalid sample size '{sample_bytes}'"))
+            })?);
+    }
+    Ok(StoredMetadata {
+        version: metadata.get(VERSION_METADATA_KEY).cloned(),
+        architecture: metadata.get(ARCHITECTURE_METADATA_KEY).cloned(),
+        config,
+        classes: metadata
+            .get(CLASSES_METADATA_KEY)
+            .map(|classes| classes.split(',').map(str::to_string).collect()),
+        class_scheme: metadata.get(CLASS_SCHEME_METADATA_KEY).cloned(),
+    })
+}
+
+fn read_metadata(path: &Path) -> Result<HashMap<String, String>> {
+    Ok(read_header(path)?.metadata().clone().unwrap_or_default())
+}
+
+fn read_header(path: &Path) -> Result<Metadata> {
+    let invalid = |source| BvError::InvalidModelFile {
+        path: path.to_path_buf(),
+        source,
+    };
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return Err(invalid(ModelFileError::NotFound))
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let file_metadata = file.metadata()?;
+    if !file_metadata.is_file() {
+        return Err(invalid(ModelFileError::NotAFile));
+    }
+    // A safetensors file starts with the little endian length of its JSON header.
+    let parse_error = |message: String| invalid(ModelFileError::Parse(message));
+    let mut prefix = [0; 8];
+    if file_metadata.len() < 8 {
+        return Err(parse_error("file is too small".to_string()));
+    }
+    file.read_exact(&mut prefix)?;
+    let header_len = u64::from_le_bytes(prefix);
+    let data_len = (file_metadata.len() - 8)
+        .checked_sub(header_len)
+        .ok_or_else(|| parse_error(format!("header of {header_len} bytes exceeds the file")))?;
+    let mut header = vec![0; header_len as usize];
+    file.read_exact(&mut header)?;
+    let metadata: Metadata = serde_json::from_slice(&header)
+        .map_err(|err| parse_error(format!("invalid header - {err}")))?;
+    let data_end = metadata
+        .tensors()
+        .values()
+        .map(|info| info.data_offsets.1)
+        .max()
+        .unwrap_or(0);
+    if data_end as u64 != data_len {
+        return Err(parse_error(format!(
+            "tensors end at {data_end} but the file holds {data_len} bytes of data"
+        )));
+    }
+    Ok(metadata)
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Optimizer {
+    Sgd { lr: f64 },
+    AdamW { lr: f64, weight_decay: f64 },
+}
+
+impl Optimizer {
+    fn build(self, vars: Vec<Var>) -> Result<TrainOptimizer> {
+        let optimizer = match self {
+            Self::Sgd { lr } => TrainOptimizer::Sgd(SGD::new(vars, lr)?),
+            Self::AdamW { lr, weight_decay } => {
+                let params = ParamsAdamW {
+                    lr,
+                    weight_decay,
+                    ..Default::default()
+                };
+                TrainOptimizer::AdamW(AdamW::new(vars, params)?)
+            }
+        };
+        Ok(optimizer)
+    }
+
+    pub fn learning_rate(self) -> f64 {
+        match self {
+            Self::Sgd { lr } | Self::AdamW { lr, .. } => lr,
+        }
+    }
+}
+
+impl Default for Optimizer {
+    fn default() -> Self {
+        Self::Sgd { lr: LEARNING_RATE }
+    }
+}
+
+/// How the learning rate of the optimizer changes from one epoch to the next.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LrSchedule {
+    #[default]
+    Constant,
+    /// Multiplies the learning rate by `gamma` after every `every` epochs.
+    Step { gamma: f64, every: usize },
+    /// Multiplies the learning rate by `gamma` after every epoch.
+    Exponential { gamma: f64 },
+}
+
+impl LrSchedule {
+    /// The learning rate of the 1-based `epoch`, starting from `base`.
+    pub fn learning_rate(self, base: f64, epoch: usize) -> f64 {
+        let decays = epoch.saturating_sub(1);
+        match self {
+            Self::Constant => base,
+            Self::Step { gamma, every } => base * gamma.powi((decays / every.max(1)) as i32),
+            Self::Exponential { gamma } => base * gamma.powi(decays as i32),
+        }
+    }
+}
+
+enum TrainOptimizer {
+    Sgd(SGD),
+    AdamW(AdamW),
+}
+
+impl TrainOptimizer {
+    fn backward_step(&mut self, loss: &Tensor, vars: &[Var], max_norm: Option<f64>) -> Result<()> {
+        let mut grads = loss.backward()?;
+        if let Some(max_norm) = max_norm {
+            clip_grad_norm(&mut grads, vars, max_norm)?;
+        }
+        match self {
+            Self::Sgd(sgd) => sgd.step(&grads)?,
+            Self::AdamW(adamw) => adamw.step(&grads)?,
+        }
+        Ok(())
+    }
+
+    fn set_learning_rate(&mut self, lr: f64) {
+        match self {
+            Self::Sgd(sgd) => sgd.set_learning_rate(lr),
+            Self::AdamW(adamw) => adamw.set_learning_rate(lr),
+        }
+    }
+}
+
+/// Scales all gradients down so their global L2 norm is at most `max_norm`.
+fn clip_grad_norm(grads: &mut GradStore, vars: &[Var], max_norm: f64) -> Result<()> {
+    let mut sum = 0.0;
+    for var in vars {
+        if let Some(grad) = grads.get(var.as_tensor()) {
+            sum += grad.sqr()?.sum_all()?.to_scalar::<f32>()? as f64;
+        }
+    }
+    let norm = sum.sqrt();
+    if norm <= max_norm {
+        return Ok(());
+    }
+    let scale = max_norm / norm;
+    for var in vars {
+        if let Some(grad) = grads.remove(var.as_tensor()) {
+            grads.insert(var.as_tensor(), grad.affine(scale, 0.0)?);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+pub struct TrainConfig {
+    pub network: NetworkConfig,
+    pub epochs: usize,
+    pub optimizer: Optimizer,
+    pub min_accuracy: f32,
+    pub batch_size: Option<usize>,
+    pub patience: Option<usize>,
+    pub min_delta: f32,
+    /// Per-class loss weights indexed by `FileType::output`, see `Dataset::class_weights`.
+    pub class_weights: Option<Vec<f32>>,
+    /// A CSV file that receives one row of metrics per epoch.
+    pub metrics_path: Option<PathBuf>,
+    /// Adds `weight_decay * sum(w^2)` over all weights to the training loss.
+    pub weight_decay: f64,
+    /// Continues from the weights already stored at the model path, if any. Falls back to
+    /// fresh weights if they cannot be loaded.
+    pub warm_start: bool,
+    /// Continues from the weights of this model, e.g. to fine-tune it on a new dataset. Unlike
+    /// `warm_start`, failing to load them or a different architecture is an error.
+    pub resume_from: Option<PathBuf>,
+    /// Clips the global L2 norm of the gradients of every step to this value.
+    pub max_grad_norm: Option<f64>,
+    /// Seeds the initial weights and the batch order to make training reproducible, see
+    /// `seed_weights`.
+    pub seed: Option<u64>,
+    pub lr_schedule: LrSchedule,
+    /// A directory to save the network to after every epoch, as `epoch_{n}.safetensors`.
+    pub checkpoint_dir: Option<PathBuf>,
+    /// Only keeps the checkpoint with the highest test accuracy so far.
+    pub keep_best: bool,
+}
+
+impl Default for TrainConfig {
+    fn default() -> Self {
+        Self {
+            network: NetworkConfig::default(),
+            epochs: EPOCHS,
+            optimizer: Optimizer::default(),
+            min_accuracy: MIN_ACCURACY,
+            batch_size: None,
+            patience: None,
+            min_delta: 0.0,
+            class_weights: None,
+            metrics_path: None,
+            weight_decay: 0.0,
+            warm_start: true,
+            resume_from: None,
+            max_grad_norm: None,
+            seed: None,
+            lr_schedule: LrSchedule::default(),
+            checkpoint_dir: None,
+            keep_best: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EpochStats {
+    pub epoch: usize,
+    pub train_loss: f32,
+    pub test_loss: f32,
+    /// Test accuracy in percent.
+    pub test_accuracy: f32,
+}
+
+pub struct TrainOutcome {
+    pub network: Network,
+    /// One entry for every epoch that ran, including the ones after the best early stopping
+    /// snapshot.
+    pub history: Vec<EpochStats>,
+    /// The device the network was trained on, as returned by `device_name`.
+    pub device: String,
+    pub dtype: DType,
+    /// The number of values in every input.
+    pub input_len: usize,
+    pub train_len: usize,
+    pub test_len: usize,
+}
+
+pub fn train<P>(m: Dataset, path: P, config: &TrainConfig, dev: &Device) -> Result<TrainOutcome>
+where
+    P: AsRef<Path>,
+{
+    let train_inputs = m.train_inputs.to_device(dev)?;
+    let train_outputs = m.train_outputs.to_device(dev)?;
+    let device = device_name(dev);
+    let dtype = train_inputs.dtype();
+    let (train_len, input_len) = train_inputs.dims2()?;
+    let test_len = m.test_outputs.dims1()?;
+    info!(
+        "Training on {device} with {} inputs of {input_len} values, {train_len} train and {test_len} test files",
+        dtype.as_str()
+    );
+    let mut varmap = VarMap::new();
+    let vs = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    let network_config = NetworkConfig {
+        normalization: m.normalization,
+        sample_bytes: m.sample_bytes,
+        ..config.network.clone()
+    };
+    let model = Network::new(vs.clone(), &network_config)?;
+    // The weights and then the batch order are drawn from the same generator.
+    let mut rng = seeded_rng(config.seed);
+    if config.seed.is_some() {
+        seed_weights(&varmap, &mut rng)?;
+    }
+    if let Some(resume_from) = &config.resume_from {
+        let restored = restore_weights(&mut varmap, resume_from, &network_config)?;
+        info!("Restored {restored} parameters from {resume_from:?}");
+    } else if config.warm_start && path.as_ref().exists() {
+        match restore_weights(&mut varmap, path.as_ref(), &network_config) {
+            Ok(restored) => info!("Restored {restored} parameters from {:?}", path.as_ref()),
+            Err(err) => warn!("Could not load existing weights, training from scratch - {err}"),
+        }
+    }
+    let vars = varmap.all_vars();
+    let mut optimizer = config.optimizer.build(vars.clone())?;
+    let test_inputs = m.test_inputs.to_device(dev)?;
     let test_outputs = m.test_outputs.to_device(dev)?;
+    let batch_size = match config.batch_size {
+        Some(0) => {
+            return Err(BvError::InvalidConfig(
+                "batch size cannot be zero".to_string(),
+            ))
+        }
+        Some(batch_size) => batch_size.min(train_len),
+        None => train_len,
+    };
+    let class_weights = match &config.class_weights {
+        Some(weights) if weights.len() != N_OUTPUT => {
+            return Err(BvError::InvalidConfig(format!(
+                "expected {N_OUTPUT} class weights, got {}",
+                weights.len()
+            )));
+        }
+        Some(weights) => Some(Tensor::new(weights.as_slice(), dev)?),
+        None => None,
+    };
+    let mut metrics = match &config.metrics_path {
+        Some(metrics_path) => {
+            let mut file = File::create(metrics_path)?;
+            writeln!(file, "epoch,train_loss,test_loss,test_accuracy")?;
+            Some(file)
+        }
+        None => None,
+    };
+    if let Some(checkpoint_dir) = &config.checkpoint_dir {
+        std::fs::create_dir_all(checkpoint_dir)?;
+    }
+    let mut best_checkpoint: Option<(PathBuf, f32)> = None;
+    let mut history = Vec::with_capacity(config.epochs);
     let mut final_accuracy: f32 = 0.0;
-    for epoch in 1..=EPOCHS {
-        let logits = model.forward(&train_inputs)?;
-        let log_sm = ops::log_softmax(&logits, D::Minus1)?;
-        let loss = loss::nll(&log_sm, &train_outputs)?;
-        sgd.backward_step(&loss)?;
-        let test_logits = model.forward(&test_inputs)?;
+    let mut best_loss = f32::INFINITY;
+    let mut best: Option<(HashMap<String, Tensor>, f32)> = None;
+    let mut stale_epochs = 0;
+    for epoch in 1..=config.epochs {
+        optimizer.set_learning_rate(
+            config
+                .lr_schedule
+                .learning_rate(config.optimizer.learning_rate(), epoch),
+        );
+        let (epoch_inputs, epoch_outputs) = if batch_size < train_len {
+            let mut indices: Vec<u32> = (0..train_len as u32).collect();
+            indices.shuffle(&mut rng);
+            let indices = Tensor::from_vec(indices, train_len, dev)?;
+            (
+                train_inputs.index_select(&indices, 0)?,
+                train_outputs.index_select(&indices, 0)?,
+            )
+        } else {
+            (train_inputs.clone(), train_outputs.clone())
+        };
+        let mut loss_sum = 0.0;
+        for start in (0..train_len).step_by(batch_size) {
+            let len = batch_size.min(train_len - start);
+            let inputs = epoch_inputs.narrow(0, start, len)?;
+            let outputs = epoch_outputs.narrow(0, start, len)?;
+            let logits = model.forward(&inputs, true)?;
+            let log_sm = ops::log_softmax(&logits, D::Minus1)?;
+            let loss = match &class_weights {
+                Some(weights) => weighted_nll(&log_sm, &outputs, weights)?,
+                None => loss::nll(&log_sm, &outputs)?,
+            };
+            loss_sum += loss.to_scalar::<f32>()? * len as f32;
+            let loss = if config.weight_decay > 0.0 {
+                (loss + l2_penalty(&varmap)?.affine(config.weight_decay, 0.0)?)?
+            } else {
+                loss
+            };
+            optimizer.backward_step(&loss, &vars, config.max_grad_norm)?;
+        }
+        let train_loss = loss_sum / train_len as f32;
+        let test_logits = model.forward(&test_inputs, false)?;
+        let test_log_sm = ops::log_softmax(&test_logits, D::Minus1)?;
+        let test_loss = loss::nll(&test_log_sm, &test_outputs)?.to_scalar::<f32>()?;
         let sum_ok = test_logits
             .argmax(D::Minus1)?
             .eq(&test_outputs)?
@@ -201,18 +1873,1790 @@ where
         let test_accuracy = sum_ok / test_outputs.dims1()? as f32;
         final_accuracy = 100.0 * test_accuracy;
         info!(
-            "Epoch: {epoch:3} Train loss: {:8.5} Test accuracy: {:5.2}%",
-            loss.to_scalar::<f32>()?,
-            final_accuracy
+            "Epoch: {epoch:3} Train loss: {train_loss:8.5} Test loss: {test_loss:8.5} Test accuracy: {final_accuracy:5.2}%"
         );
+        if let Some(metrics) = &mut metrics {
+            writeln!(metrics, "{epoch},{train_loss},{test_loss},{final_accuracy}")?;
+            metrics.flush()?;
+        }
+        history.push(EpochStats {
+            epoch,
+            train_loss,
+            test_loss,
+            test_accuracy: final_accuracy,
+        });
+        if let Some(checkpoint_dir) = &config.checkpoint_dir {
+            let improved = best_checkpoint
+                .as_ref()
+                .is_none_or(|(_, accuracy)| final_accuracy > *accuracy);
+            if !config.keep_best || improved {
+                let checkpoint = checkpoint_dir.join(format!("epoch_{epoch}.safetensors"));
+                model.save(&varmap, &checkpoint)?;
+                info!("Checkpoint written to {checkpoint:?} ({final_accuracy:.2}% test accuracy)");
+                let previous = best_checkpoint.replace((checkpoint, final_accuracy));
+                if let Some((previous, _)) = previous.filter(|_| config.keep_best) {
+                    std::fs::remove_file(previous)?;
+                }
+            }
+        }
         if final_accuracy == 100.0 {
             break;
         }
+        if let Some(patience) = config.patience {
+            if test_loss < best_loss - config.min_delta {
+                best_loss = test_loss;
+                best = Some((snapshot(&varmap)?, final_accuracy));
+                stale_epochs = 0;
+            } else {
+                stale_epochs += 1;
+                if stale_epochs >= patience {
+                    info!("Test loss did not improve for {patience} epochs, stopping early");
+                    break;
+                }
+            }
+        }
+    }
+    if let Some((weights, accuracy)) = best.filter(|_| final_accuracy < 100.0) {
+        restore(&varmap, &weights)?;
+        final_accuracy = accuracy;
     }
-    _ = varmap.save(&path);
-    if final_accuracy < 95.0 {
-        Err(anyhow::Error::msg("The model is not trained well enough."))
+    _ = model.save(&varmap, &path);
+    let confusion = confusion_matrix(&model, &test_inputs, &test_outputs)?;
+    log_confusion_matrix(&confusion);
+    if final_accuracy < config.min_accuracy {
+        Err(BvError::ModelNotConverged {
+            accuracy: final_accuracy,
+        })
     } else {
-        Ok(model)
+        Ok(TrainOutcome {
+            network: model,
+            history,
+            device,
+            dtype,
+            input_len,
+            train_len,
+            test_len,
+        })
+    }
+}
+
+/// Negative log likelihood where every sample counts with the weight of its class.
+fn weighted_nll(log_sm: &Tensor, targets: &Tensor, weights: &Tensor) -> Result<Tensor> {
+    let picked = log_sm.gather(&targets.unsqueeze(1)?, 1)?.squeeze(1)?;
+    let sample_weights = weights.index_select(targets, 0)?;
+    let total = (picked * &sample_weights)?.sum_all()?.neg()?;
+    Ok((total / sample_weights.sum_all()?)?)
+}
+
+fn l2_penalty(varmap: &VarMap) -> Result<Tensor> {
+    let squares = varmap
+        .all_vars()
+        .iter()
+        .map(|var| var.as_tensor().sqr()?.sum_all())
+        .collect::<candle::Result<Vec<_>>>()?;
+    Ok(Tensor::stack(&squares, 0)?.sum_all()?)
+}
+
+/// A generator seeded with `seed`, or from system entropy without one.
+pub fn seeded_rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
+/// Re-initializes every weight from `rng`, drawing from the same distributions as
+/// `candle_nn::linear`.
+pub fn seed_weights(varmap: &VarMap, rng: &mut StdRng) -> Result<()> {
+    let vars = varmap.data().lock().expect("VarMap lock poisoned");
+    let mut names = vars.keys().collect::<Vec<_>>();
+    names.sort();
+    for name in names {
+        let var = &vars[name];
+        let values = match var.dims() {
+            &[out_dim, in_dim] => {
+                let std = (2.0 / in_dim as f64).sqrt();
+                (0..out_dim * in_dim)
+                    .map(|_| (standard_normal(rng) * std) as f32)
+                    .collect::<Vec<_>>()
+            }
+            &[out_dim] => {
+                let weight = name.replace(".bias", ".weight");
+                let in_dim = vars.get(&weight).map_or(out_dim, |weight| weight.dims()[1]);
+                let bound = 1.0 / (in_dim as f64).sqrt();
+                (0..out_dim)
+                    .map(|_| rng.gen_range(-bound..bound) as f32)
+                    .collect::<Vec<_>>()
+            }
+            dims => {
+                return Err(BvError::InvalidModel(format!(
+                    "cannot seed {name} with shape {dims:?}"
+                )))
+            }
+        };
+        var.set(&Tensor::from_vec(values, var.shape(), var.device())?)?;
+    }
+    Ok(())
+}
+
+/// Box-Muller transform, since `rand` itself has no normal distribution.
+fn standard_normal(rng: &mut StdRng) -> f64 {
+    let u1 = 1.0 - rng.gen::<f64>();
+    let u2 = rng.gen::<f64>();
+    (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos()
+}
+
+fn snapshot(varmap: &VarMap) -> Result<HashMap<String, Tensor>> {
+    let vars = varmap.data().lock().expect("VarMap lock poisoned");
+    let mut weights = HashMap::with_capacity(vars.len());
+    for (name, var) in vars.iter() {
+        weights.insert(name.clone(), var.as_tensor().copy()?);
+    }
+    Ok(weights)
+}
+
+fn restore(varmap: &VarMap, weights: &HashMap<String, Tensor>) -> Result<()> {
+    let vars = varmap.data().lock().expect("VarMap lock poisoned");
+    for (name, var) in vars.iter() {
+        if let Some(weight) = weights.get(name) {
+            var.set(weight)?;
+        }
+    }
+    Ok(())
+}
+
+/// Counts test predictions with rows indexed by the true label and columns by the prediction.
+pub fn confusion_matrix(
+    network: &Network,
+    inputs: &Tensor,
+    outputs: &Tensor,
+) -> Result<Vec<Vec<u32>>> {
+    let logits = network.forward(inputs, false)?;
+    let predictions = logits.argmax(D::Minus1)?.to_vec1::<u32>()?;
+    let outputs = outputs.to_vec1::<u32>()?;
+    let mut matrix = vec![vec![0; N_OUTPUT]; N_OUTPUT];
+    for (output, prediction) in outputs.into_iter().zip(predictions) {
+        if let Some(predicted) = network.file_type(prediction as usize) {
+            matrix[output as usize][predicted.output() as usize] += 1;
+        }
+    }
+    Ok(matrix)
+}
+
+pub struct Evaluation {
+    pub confusion: Vec<Vec<u32>>,
+    /// Files with an unknown extension.
+    pub skipped: usize,
+    /// Files that could not be read, left out of the confusion matrix.
+    pub unreadable: usize,
+}
+
+impl Evaluation {
+    pub fn accuracy(&self) -> f32 {
+        let total: u32 = self.confusion.iter().flatten().sum();
+        if total == 0 {
+            return 0.0;
+        }
+        let correct: u32 = (0..self.confusion.len())
+            .map(|i| self.confusion[i][i])
+            .sum();
+        100.0 * correct as f32 / total as f32
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ClassMetrics {
+    pub precision: f32,
+    pub recall: f32,
+    pub f1: f32,
+    /// The number of files that truly belong to the class.
+    pub support: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Report {
+    /// Indexed like the rows of the confusion matrix.
+    pub classes: Vec<ClassMetrics>,
+    /// The unweighted mean over every class that occurs or was predicted.
+    pub macro_avg: ClassMetrics,
+    /// The mean over all classes weighted by their support.
+    pub weighted_avg: ClassMetrics,
+}
+
+/// Computes precision, recall and F1 score of every class of a confusion matrix whose rows are
+/// the true labels. All values are fractions between 0 and 1.
+pub fn classification_report(confusion: &[Vec<u32>]) -> Report {
+    let ratio = |a: u32, b: u32| if b == 0 { 0.0 } else { a as f32 / b as f32 };
+    let mut classes = Vec::with_capacity(confusion.len());
+    let mut present = 0;
+    let mut macro_avg = ClassMetrics::default();
+    let mut weighted_avg = ClassMetrics::default();
+    for (i, row) in confusion.iter().enumerate() {
+        let true_positives = row[i];
+        let predicted: u32 = confusion.iter().map(|row| row[i]).sum();
+        let support: u32 = row.iter().sum();
+        let precision = ratio(true_positives, predicted);
+        let recall = ratio(true_positives, support);
+        let f1 = if precision + recall > 0.0 {
+            2.0 * precision * recall / (precision + recall)
+        } else {
+            0.0
+        };
+        if support > 0 || predicted > 0 {
+            present += 1;
+            macro_avg.precision += precision;
+            macro_avg.recall += recall;
+            macro_avg.f1 += f1;
+        }
+        weighted_avg.precision += precision * support as f32;
+        weighted_avg.recall += recall * support as f32;
+        weighted_avg.f1 += f1 * support as f32;
+        weighted_avg.support += support;
+        classes.push(ClassMetrics {
+            precision,
+            recall,
+            f1,
+            support,
+        });
+    }
+    macro_avg.support = weighted_avg.support;
+    let total = weighted_avg.support as f32;
+    for (avg, len) in [(&mut macro_avg, present as f32), (&mut weighted_avg, total)] {
+        if len > 0.0 {
+            avg.precision /= len;
+            avg.recall /= len;
+            avg.f1 /= len;
+        }
+    }
+    Report {
+        classes,
+        macro_avg,
+        weighted_avg,
+    }
+}
+
+/// Predicts every labeled file below `path` and tallies the results in a confusion matrix.
+///
+/// Files are picked and labeled by `config` like for training, while the normalization and
+/// sample size always come from the network.
+pub fn evaluate<P>(
+    network: &Network,
+    path: P,
+    config: &DatasetConfig,
+    dev: &Device,
+) -> Result<Evaluation>
+where
+    P: AsRef<Path>,
+{
+    let config = DatasetConfig {
+        normalization: network.config.normalization,
+        sample_bytes: network.config.sample_bytes,
+        ..config.clone()
+    };
+    let (files, skipped) = labeled_files(path, &config)?;
+    let files = files.into_iter().map(|(_, path)| path).collect();
+    let mut batches = Batches::new(files, config, EVAL_BATCH_SIZE, dev);
+    let mut confusion = vec![vec![0; N_OUTPUT]; N_OUTPUT];
+    for batch in &mut batches {
+        let (inputs, outputs) = batch?;
+        let batch_confusion = confusion_matrix(network, &inputs, &outputs)?;
+        for (row, batch_row) in confusion.iter_mut().zip(batch_confusion) {
+            for (count, batch_count) in row.iter_mut().zip(batch_row) {
+                *count += batch_count;
+            }
+        }
+    }
+    Ok(Evaluation {
+        confusion,
+        skipped,
+        unreadable: batches.unreadable,
+    })
+}
+
+pub fn log_confusion_matrix(matrix: &[Vec<u32>]) {
+    let header: String = FileType::ALL
+        .iter()
+        .map(|typ| format!("{:>8}", format!("{typ:?}")))
+        .collect();
+    info!("Confusion matrix (rows: true, columns: predicted)");
+    info!("{:8}{header}", "");
+    for (typ, row) in FileType::ALL.iter().zip(matrix) {
+        let row: String = row.iter().map(|count| format!("{count:>8}")).collect();
+        info!("{:>8}{row}", format!("{typ:?}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tiny_network() -> Network {
+        tiny_network_with_weights().1
+    }
+
+    /// A tiny network along with the `VarMap` that holds its weights, for saving it.
+    fn tiny_network_with_weights() -> (VarMap, Network) {
+        let varmap = VarMap::new();
+        let vs = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        let config = NetworkConfig {
+            hidden: vec![4],
+            ..Default::default()
+        };
+        let network = Network::new(vs, &config).unwrap();
+        (varmap, network)
+    }
+
+    fn fresh_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bv-{name}-{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A network whose largest logit is `output` for every input.
+    fn constant_network(output: usize) -> Network {
+        let mut bias = [0f32; N_OUTPUT];
+        bias[output] = 10.0;
+        biased_network(bias)
+    }
+
+    /// A network whose logits are `bias` for every input.
+    fn biased_network(bias: [f32; N_OUTPUT]) -> Network {
+        let mut network = tiny_network();
+        let weight = Tensor::zeros((N_OUTPUT, 4), DType::F32, &Device::Cpu).unwrap();
+        let bias = Tensor::new(&bias, &Device::Cpu).unwrap();
+        *network.layers.last_mut().unwrap() = Linear::new(weight, Some(bias));
+        network
+    }
+
+    /// A network where input `routes[i].0` only activates hidden unit `i`, which only raises the
+    /// logit of output `routes[i].1`.
+    fn routing_network(routes: &[(usize, usize)]) -> Network {
+        let mut network = tiny_network();
+        let mut first = vec![0f32; 4 * N_INPUT];
+        let mut last = vec![0f32; N_OUTPUT * 4];
+        for (i, &(input, output)) in routes.iter().enumerate() {
+            first[i * N_INPUT + input] = 1.0;
+            last[output * 4 + i] = 1.0;
+        }
+        let linear = |weight, dims| {
+            let weight = Tensor::from_vec(weight, dims, &Device::Cpu).unwrap();
+            Linear::new(weight, None)
+        };
+        network.layers = vec![linear(first, (4, N_INPUT)), linear(last, (N_OUTPUT, 4))];
+        network
+    }
+
+    fn temp_model(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("bv-{name}-{}.safetensors", std::process::id()))
+    }
+
+    /// Saves a tiny network with the metadata entry `key` replaced by `value`.
+    fn save_with_metadata(name: &str, key: &str, value: &str) -> PathBuf {
+        let (varmap, network) = tiny_network_with_weights();
+        let model = temp_model(name);
+        network.save(&varmap, &model).unwrap();
+        let mut metadata = read_metadata(&model).unwrap();
+        metadata.insert(key.to_string(), value.to_string());
+        let vars = varmap.data().lock().unwrap();
+        let tensors = vars.iter().map(|(name, var)| (name, var.as_tensor()));
+        safetensors::tensor::serialize_to_file(tensors, &Some(metadata), &model).unwrap();
+        model
+    }
+
+    /// Writes `count` files of distinct content, lowercase letters if `text` and random bytes
+    /// otherwise.
+    fn write_files(dir: &Path, ext: &str, count: usize, text: bool) {
+        for i in 0..count {
+            let mut state = (i as u32 + 1).wrapping_mul(2654435761);
+            let content: Vec<u8> = (0..512)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    if text {
+                        b'a' + (state % 26) as u8
+                    } else {
+                        state as u8
+                    }
+                })
+                .collect();
+            std::fs::write(dir.join(format!("{i}.{ext}")), content).unwrap();
+        }
+    }
+
+    /// Creates a fresh directory with `count` files for every extension, text for `txt`.
+    fn dataset_dir(name: &str, files: &[(&str, usize)]) -> PathBuf {
+        let dir = fresh_dir(name);
+        for &(ext, count) in files {
+            write_files(&dir, ext, count, ext == "txt");
+        }
+        dir
+    }
+
+    /// Text and random files whose validation set swaps both contents, so the test accuracy
+    /// never reaches 100% and training runs every epoch.
+    fn contradicting_dataset(name: &str) -> Dataset {
+        let dir = dataset_dir(name, &[("txt", 4), ("wav", 4)]);
+        let valid_dir = fresh_dir(&format!("{name}-valid"));
+        write_files(&valid_dir, "txt", 4, false);
+        write_files(&valid_dir, "wav", 4, true);
+        let config = DatasetConfig {
+            valid_dir: Some(valid_dir.clone()),
+            seed: Some(1),
+            ..Default::default()
+        };
+        let dataset = Dataset::collect(&dir, &config, &Device::Cpu).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_dir_all(valid_dir).unwrap();
+        dataset
+    }
+
+    fn tiny_train_config(epochs: usize) -> TrainConfig {
+        TrainConfig {
+            network: NetworkConfig {
+                hidden: vec![4],
+                ..Default::default()
+            },
+            epochs,
+            min_accuracy: 0.0,
+            warm_start: false,
+            seed: Some(1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn threshold_rejects_uncertain_predictions() {
+        let uniform = [1.0 / N_OUTPUT as f32; N_OUTPUT];
+        let classes = FileType::ALL;
+        assert_eq!(
+            FileType::from_prediction_with_threshold(&uniform, &classes, 0.5),
+            None
+        );
+        let mut confident = [0.01; N_OUTPUT];
+        confident[3] = 0.92;
+        let mut reordered = classes;
+        reordered.swap(3, 4);
+        assert_eq!(
+            FileType::from_prediction_with_threshold(&confident, &reordered, 0.5),
+            Some(reordered[3])
+        );
+    }
+
+    #[test]
+    fn same_seed_collects_and_reshuffles_the_same_split() {
+        let dir = dataset_dir("seed", &[("txt", 5), ("wav", 5)]);
+        let config = DatasetConfig {
+            seed: Some(7),
+            ..Default::default()
+        };
+        let a = Dataset::collect(&dir, &config, &Device::Cpu).unwrap();
+        let b = Dataset::collect(&dir, &config, &Device::Cpu).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            a.train_inputs.to_vec2::<f32>().unwrap(),
+            b.train_inputs.to_vec2::<f32>().unwrap()
+        );
+        assert_eq!(
+            a.test_outputs.to_vec1::<u32>().unwrap(),
+            b.test_outputs.to_vec1::<u32>().unwrap()
+        );
+        let a = a.reshuffle(&mut seeded_rng(Some(3))).unwrap();
+        let b = b.reshuffle(&mut seeded_rng(Some(3))).unwrap();
+        assert_eq!(
+            a.test_inputs.to_vec2::<f32>().unwrap(),
+            b.test_inputs.to_vec2::<f32>().unwrap()
+        );
+    }
+
+    #[test]
+    fn scan_with_step_larger_than_window() {
+        let network = tiny_network();
+        let bytes: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let results = network.scan(&bytes, 1000, 10000, &Device::Cpu).unwrap();
+        let offsets: Vec<usize> = results.iter().map(|&(offset, ..)| offset).collect();
+        assert_eq!(offsets, [0]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_extension_is_unknown() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let path = Path::new(OsStr::from_bytes(b"data.t\xffxt"));
+        assert_eq!(FileType::from_path(path), None);
+        let path = Path::new(OsStr::from_bytes(b"d\xffta.txt"));
+        assert_eq!(FileType::from_path(path), Some(FileType::Text));
+    }
+
+    #[test]
+    fn probabilities_sum_to_one() {
+        let network = tiny_network();
+        let mut table = BinaryTable::new();
+        table.parse(b"probabilities of a known input");
+        let probabilities = network.predict_probabilities(&table, &Device::Cpu).unwrap();
+        let sum: f32 = probabilities.iter().sum();
+        assert!((sum - 1.0).abs() < 1e-5, "sum {sum}");
+        assert!(probabilities.iter().all(|&p| (0.0..=1.0).contains(&p)));
+    }
+
+    #[test]
+    fn predict_returns_index_of_largest_logit() {
+        let network = constant_network(3);
+        let mut table = BinaryTable::new();
+        table.parse(b"any input");
+        assert_eq!(network.predict(&table, &Device::Cpu).unwrap(), 3);
+    }
+
+    #[test]
+    fn configurable_layers_forward() {
+        let varmap = VarMap::new();
+        let vs = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        let config = NetworkConfig {
+            hidden: vec![8, 6, 4],
+            ..Default::default()
+        };
+        let network = Network::new(vs, &config).unwrap();
+        assert_eq!(network.layers.len(), 4);
+        let input = Tensor::zeros((2, N_INPUT), DType::F32, &Device::Cpu).unwrap();
+        let logits = network.forward(&input, false).unwrap();
+        assert_eq!(logits.dims(), [2, N_OUTPUT]);
+
+        let varmap = VarMap::new();
+        let vs = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        let config = NetworkConfig {
+            input: 64 * 64,
+            hidden: vec![4],
+            ..Default::default()
+        };
+        let network = Network::new(vs, &config).unwrap();
+        let mut table = BinaryTable::with_bits(6).unwrap();
+        table.parse(b"a coarse table");
+        assert!(network.predict(&table, &Device::Cpu).is_ok());
+        assert!(matches!(
+            network.predict(&BinaryTable::from_counts([[1; 256]; 256]), &Device::Cpu),
+            Err(BvError::ShapeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn adamw_lowers_the_training_loss() {
+        let dataset = contradicting_dataset("adamw");
+        let config = TrainConfig {
+            optimizer: Optimizer::AdamW {
+                lr: 0.01,
+                weight_decay: 0.0,
+            },
+            ..tiny_train_config(2)
+        };
+        let model = temp_model("adamw");
+        let outcome = train(dataset, &model, &config, &Device::Cpu).unwrap();
+        _ = std::fs::remove_file(model);
+        let [first, second] = outcome.history[..] else {
+            panic!("expected two epochs, got {:?}", outcome.history);
+        };
+        assert!(second.train_loss < first.train_loss, "{first:?} {second:?}");
+    }
+
+    #[test]
+    fn unreached_min_accuracy_is_an_error() {
+        let defaults = TrainConfig::default();
+        assert_eq!(defaults.epochs, EPOCHS);
+        assert_eq!(defaults.min_accuracy, MIN_ACCURACY);
+        let config = TrainConfig {
+            min_accuracy: 100.0,
+            ..tiny_train_config(1)
+        };
+        let model = temp_model("min-accuracy");
+        let result = train(
+            contradicting_dataset("min-accuracy"),
+            &model,
+            &config,
+            &Device::Cpu,
+        );
+        _ = std::fs::remove_file(model);
+        assert!(matches!(result, Err(BvError::ModelNotConverged { .. })));
+    }
+
+    #[test]
+    fn split_sets_the_train_fraction() {
+        let dir = dataset_dir("split", &[("txt", 10)]);
+        let config = DatasetConfig {
+            split: 0.7,
+            ..Default::default()
+        };
+        let dataset = Dataset::collect(&dir, &config, &Device::Cpu);
+        let invalid = DatasetConfig {
+            split: 1.0,
+            ..Default::default()
+        };
+        let rejected = Dataset::collect(&dir, &invalid, &Device::Cpu);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let dataset = dataset.unwrap();
+        assert_eq!(dataset.train_outputs.dims1().unwrap(), 7);
+        assert_eq!(dataset.test_outputs.dims1().unwrap(), 3);
+        assert!(matches!(rejected, Err(BvError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn every_class_lands_on_both_sides_of_the_split() {
+        let dir = dataset_dir("stratified", &[("txt", 8), ("wav", 2)]);
+        let config = DatasetConfig {
+            split: 0.5,
+            seed: Some(2),
+            ..Default::default()
+        };
+        let dataset = Dataset::collect(&dir, &config, &Device::Cpu);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let dataset = dataset.unwrap();
+        for outputs in [&dataset.train_outputs, &dataset.test_outputs] {
+            let outputs = outputs.to_vec1::<u32>().unwrap();
+            assert!(outputs.contains(&FileType::Text.output()));
+            assert!(outputs.contains(&FileType::Wav.output()));
+        }
+    }
+
+    fn sorted_rows(tensors: &[&Tensor]) -> Vec<Vec<f32>> {
+        let mut rows = Vec::new();
+        for tensor in tensors {
+            rows.extend(tensor.to_vec2::<f32>().unwrap());
+        }
+        rows.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        rows
+    }
+
+    #[test]
+    fn parallel_collect_matches_sequential_parsing() {
+        let dir = dataset_dir("parallel", &[("txt", 12), ("wav", 12)]);
+        let dataset = Dataset::collect(&dir, &DatasetConfig::default(), &Device::Cpu).unwrap();
+        let mut sequential = Vec::new();
+        let mut table = BinaryTable::new();
+        walk_dir(&dir, false, &PathFilter::default(), &mut |path| {
+            table.parse(&std::fs::read(path)?);
+            sequential.push(table.export());
+            table.clear();
+            Ok(())
+        })
+        .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        sequential.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            sorted_rows(&[&dataset.train_inputs, &dataset.test_inputs]),
+            sequential
+        );
+    }
+
+    #[test]
+    fn batches_cover_every_file_once() {
+        let dir = dataset_dir("batched", &[("txt", 4), ("wav", 4)]);
+        let config = DatasetConfig {
+            seed: Some(5),
+            normalization: Normalization::Linear,
+            sample_bytes: Some(64),
+            ..Default::default()
+        };
+        let batches = Dataset::collect_batched(&dir, &config, 4, &Device::Cpu)
+            .unwrap()
+            .collect::<Result<Vec<_>>>();
+        let dataset = Dataset::collect(&dir, &config, &Device::Cpu).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let batches = batches.unwrap();
+        assert_eq!(batches.len(), 2);
+        for (inputs, outputs) in &batches {
+            assert_eq!(inputs.dims2().unwrap(), (4, N_INPUT));
+            assert_eq!(outputs.dims1().unwrap(), 4);
+        }
+        let batched = sorted_rows(&[&batches[0].0, &batches[1].0]);
+        assert!(batched.windows(2).all(|pair| pair[0] != pair[1]));
+        assert_eq!(
+            batched,
+            sorted_rows(&[&dataset.train_inputs, &dataset.test_inputs])
+        );
+    }
+
+    #[test]
+    fn minibatches_keep_the_loss_finite() {
+        let dataset = contradicting_dataset("minibatch");
+        let config = TrainConfig {
+            batch_size: Some(2),
+            ..tiny_train_config(2)
+        };
+        let model = temp_model("minibatch");
+        let outcome = train(dataset, &model, &config, &Device::Cpu).unwrap();
+        _ = std::fs::remove_file(model);
+        assert_eq!(outcome.history.len(), 2);
+        for stats in &outcome.history {
+            assert!(stats.train_loss.is_finite(), "{stats:?}");
+            assert!(stats.test_loss.is_finite(), "{stats:?}");
+        }
+    }
+
+    #[test]
+    fn confusion_matrix_counts_true_labels_by_prediction() {
+        let network = constant_network(3);
+        let inputs = Tensor::zeros((3, N_INPUT), DType::F32, &Device::Cpu).unwrap();
+        let outputs = Tensor::new(&[0u32, 3, 3], &Device::Cpu).unwrap();
+        let matrix = confusion_matrix(&network, &inputs, &outputs).unwrap();
+        assert_eq!(matrix.len(), N_OUTPUT);
+        assert_eq!(matrix[0][3], 1);
+        assert_eq!(matrix[3][3], 2);
+        assert_eq!(matrix.iter().flatten().sum::<u32>(), 3);
+    }
+
+    #[test]
+    fn plateaued_test_loss_stops_training_early() {
+        let dataset = contradicting_dataset("patience");
+        let config = TrainConfig {
+            patience: Some(2),
+            // No epoch after the first can improve by this much.
+            min_delta: 1e9,
+            ..tiny_train_config(10)
+        };
+        let model = temp_model("patience");
+        let outcome = train(dataset, &model, &config, &Device::Cpu).unwrap();
+        _ = std::fs::remove_file(model);
+        assert_eq!(outcome.history.len(), 3);
+    }
+
+    #[test]
+    fn parse_device_specs() {
+        assert!(matches!(parse_device("cpu"), Ok(Device::Cpu)));
+        assert_eq!(
+            parse_device("cuda:0").is_ok(),
+            candle::utils::cuda_is_available()
+        );
+        for spec in ["tpu", "cuda:x", ""] {
+            assert!(
+                matches!(parse_device(spec), Err(BvError::UnsupportedDevice(_))),
+                "{spec}"
+            );
+        }
+    }
+
+    #[test]
+    fn magic_bytes_detect_the_file_type() {
+        assert_eq!(
+            FileType::from_magic(b"\xFF\xD8\xFF\xE0rest"),
+            Some(FileType::Jpeg)
+        );
+        assert_eq!(FileType::from_magic(b"%PDF-1.7"), Some(FileType::Pdf));
+        assert_eq!(
+            FileType::from_magic(b"RIFF\x24\x08\x00\x00WAVEfmt "),
+            Some(FileType::Wav)
+        );
+        assert_eq!(FileType::from_magic(b"RIFF\x24\x08\x00\x00AVI "), None);
+        assert_eq!(FileType::from_magic(b"\x89PNG\r\n\x1a\n"), None);
+        assert_eq!(FileType::from_magic(b""), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_loop_terminates() {
+        let dir = dataset_dir("symlinks", &[("txt", 2)]);
+        std::fs::create_dir(dir.join("nested")).unwrap();
+        std::os::unix::fs::symlink(&dir, dir.join("nested").join("loop")).unwrap();
+        let mut files = Vec::new();
+        let walked = walk_dir(&dir, true, &PathFilter::default(), &mut |path| {
+            files.push(path);
+            Ok(())
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+        walked.unwrap();
+        assert_eq!(files.len(), 2);
+    }
+
+    #[test]
+    fn dropout_only_varies_in_train_mode() {
+        let varmap = VarMap::new();
+        let vs = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        let config = NetworkConfig {
+            hidden: vec![64],
+            dropout: 0.5,
+            ..Default::default()
+        };
+        let network = Network::new(vs, &config).unwrap();
+        let mut table = BinaryTable::new();
+        table.parse(b"dropout should only apply while training the network");
+        let input = Tensor::new(table.export().as_slice(), &Device::Cpu)
+            .unwrap()
+            .unsqueeze(0)
+            .unwrap();
+        let logits = |train| {
+            let logits = network.forward(&input, train).unwrap();
+            logits.to_vec2::<f32>().unwrap()
+        };
+        assert_eq!(logits(false), logits(false));
+        assert_ne!(logits(true), logits(true));
+    }
+
+    #[test]
+    fn saved_class_list_is_loaded_back() {
+        let (varmap, mut network) = tiny_network_with_weights();
+        network.classes.reverse();
+        let model = temp_model("classes");
+        network.save(&varmap, &model).unwrap();
+        let loaded = Network::load(&model, &Device::Cpu);
+        std::fs::remove_file(model).unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.classes, network.classes);
+        assert_eq!(loaded.file_type(0), Some(FileType::Unknown));
+    }
+
+    #[test]
+    fn evaluate_scores_a_labeled_directory() {
+        let dir = dataset_dir("evaluate", &[("txt", 3), ("wav", 1), ("xyz", 2)]);
+        let network = constant_network(FileType::Text.output() as usize);
+        let evaluation = evaluate(&network, &dir, &DatasetConfig::default(), &Device::Cpu);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let evaluation = evaluation.unwrap();
+        let text = FileType::Text.output() as usize;
+        let wav = FileType::Wav.output() as usize;
+        assert_eq!(evaluation.skipped, 2);
+        assert_eq!(evaluation.confusion[text][text], 3);
+        assert_eq!(evaluation.confusion[wav][text], 1);
+        assert_eq!(evaluation.accuracy(), 75.0);
+    }
+
+    /// 18 Text and 2 Wav rows of two features where Text also outnumbers Wav on the Wav
+    /// feature, so an unweighted loss never predicts Wav.
+    fn imbalanced_dataset() -> Dataset {
+        let text = FileType::Text.output();
+        let wav = FileType::Wav.output();
+        let mut inputs = Vec::new();
+        let mut outputs = Vec::new();
+        for (input, output, count) in [([1.0, 0.0], text, 14), ([0.0, 1.0], text, 4)] {
+            inputs.extend(std::iter::repeat_n(input, count).flatten());
+            outputs.extend(std::iter::repeat_n(output, count));
+        }
+        inputs.extend([0.0, 1.0, 0.0, 1.0]);
+        outputs.extend([wav, wav]);
+        let inputs = Tensor::from_vec(inputs, (20, 2), &Device::Cpu).unwrap();
+        let outputs = Tensor::new(outputs.as_slice(), &Device::Cpu).unwrap();
+        Dataset {
+            train_inputs: inputs.clone(),
+            train_outputs: outputs.clone(),
+            test_inputs: inputs,
+            test_outputs: outputs,
+            normalization: Normalization::default(),
+            unreadable: 0,
+            sample_bytes: None,
+        }
+    }
+
+    #[test]
+    fn class_weights_raise_minority_recall() {
+        let wav = FileType::Wav.output() as usize;
+        let recall = |class_weights: Option<Vec<f32>>| {
+            let dataset = imbalanced_dataset();
+            let (inputs, outputs) = (dataset.test_inputs.clone(), dataset.test_outputs.clone());
+            let mut config = TrainConfig {
+                optimizer: Optimizer::AdamW {
+                    lr: 0.05,
+                    weight_decay: 0.0,
+                },
+                class_weights,
+                ..tiny_train_config(100)
+            };
+            config.network.input = 2;
+            let model = temp_model("class-weights");
+            let outcome = train(dataset, &model, &config, &Device::Cpu).unwrap();
+            _ = std::fs::remove_file(model);
+            let confusion = confusion_matrix(&outcome.network, &inputs, &outputs).unwrap();
+            confusion[wav][wav] as f32 / 2.0
+        };
+        let weights = imbalanced_dataset().class_weights().unwrap();
+        let unweighted = recall(None);
+        let weighted = recall(Some(weights));
+        assert!(weighted > unweighted, "{weighted} <= {unweighted}");
+    }
+
+    #[test]
+    fn errors_have_matchable_variants() {
+        let dir = dataset_dir("too-small", &[("txt", 1)]);
+        let result = Dataset::collect(&dir, &DatasetConfig::default(), &Device::Cpu);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(result, Err(BvError::DatasetTooSmall)));
+
+        let result = Dataset::collect(&dir, &DatasetConfig::default(), &Device::Cpu);
+        assert!(
+            matches!(result, Err(BvError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound)
+        );
+
+        let product = || -> Result<Tensor> {
+            let a = Tensor::zeros((2, 3), DType::F32, &Device::Cpu)?;
+            Ok(a.matmul(&a)?)
+        };
+        assert!(matches!(product(), Err(BvError::Candle(_))));
+    }
+
+    #[test]
+    fn topk_is_sorted_and_clamped() {
+        let network = biased_network([0.0, 3.0, 1.0, 2.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+        let mut table = BinaryTable::new();
+        table.parse(b"any input");
+        let top = network.predict_topk(&table, &Device::Cpu, 3).unwrap();
+        let types: Vec<FileType> = top.iter().map(|&(typ, _)| typ).collect();
+        assert_eq!(
+            types,
+            [FileType::ALL[1], FileType::ALL[3], FileType::ALL[2]]
+        );
+        assert!(top.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+        assert!(top.iter().map(|&(_, p)| p).sum::<f32>() <= 1.0);
+
+        let all = network.predict_topk(&table, &Device::Cpu, 100).unwrap();
+        assert_eq!(all.len(), N_OUTPUT);
+        assert!((all.iter().map(|&(_, p)| p).sum::<f32>() - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn metrics_csv_has_a_row_per_epoch() {
+        let metrics = std::env::temp_dir().join(format!("bv-metrics-{}.csv", std::process::id()));
+        let config = TrainConfig {
+            metrics_path: Some(metrics.clone()),
+            ..tiny_train_config(2)
+        };
+        let model = temp_model("metrics");
+        let outcome = train(
+            contradicting_dataset("metrics"),
+            &model,
+            &config,
+            &Device::Cpu,
+        );
+        _ = std::fs::remove_file(model);
+        let csv = std::fs::read_to_string(&metrics);
+        _ = std::fs::remove_file(metrics);
+        outcome.unwrap();
+        let csv = csv.unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "epoch,train_loss,test_loss,test_accuracy");
+        assert!(lines[1].starts_with("1,"));
+        assert!(lines[2].starts_with("2,"));
+    }
+
+    #[test]
+    fn max_per_class_caps_the_collected_files() {
+        let dir = dataset_dir("limit", &[("txt", 20)]);
+        let config = DatasetConfig {
+            max_per_class: Some(5),
+            ..Default::default()
+        };
+        let dataset = Dataset::collect(&dir, &config, &Device::Cpu);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let dataset = dataset.unwrap();
+        let len = dataset.train_outputs.dims1().unwrap() + dataset.test_outputs.dims1().unwrap();
+        assert_eq!(len, 5);
+    }
+
+    #[test]
+    fn different_architecture_is_a_version_mismatch() {
+        let model = save_with_metadata("architecture", ARCHITECTURE_METADATA_KEY, "65536-999-9");
+        let result = Network::load(&model, &Device::Cpu);
+        std::fs::remove_file(model).unwrap();
+        assert!(matches!(
+            result,
+            Err(BvError::ModelVersionMismatch { found, .. }) if found == "65536-999-9"
+        ));
+    }
+
+    #[test]
+    fn summary_counts_mixed_and_unknown_extensions() {
+        let dir = dataset_dir("summary", &[("txt", 4), ("wav", 2), ("xyz", 3)]);
+        let summary = Dataset::summarize(&dir, &DatasetConfig::default());
+        std::fs::remove_dir_all(&dir).unwrap();
+        let summary = summary.unwrap();
+        assert_eq!(summary.counts[FileType::Text.output() as usize], 4);
+        assert_eq!(summary.counts[FileType::Wav.output() as usize], 2);
+        assert_eq!(summary.counts.iter().sum::<usize>(), 6);
+        assert_eq!(summary.skipped, 3);
+        assert_eq!(summary.bytes, 6 * 512);
+        assert_eq!((summary.train_len, summary.test_len), (4, 2));
+    }
+
+    #[test]
+    fn progress_is_reported_once_per_file() {
+        let dir = dataset_dir("progress", &[("txt", 4), ("wav", 2)]);
+        let calls = std::sync::Mutex::new(Vec::new());
+        let dataset = Dataset::collect_with_progress(
+            &dir,
+            &DatasetConfig::default(),
+            &Device::Cpu,
+            |done, total| {
+                calls.lock().unwrap().push((done, total));
+            },
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        dataset.unwrap();
+        let mut calls = calls.into_inner().unwrap();
+        calls.sort();
+        assert_eq!(calls, (1..=6).map(|done| (done, 6)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn custom_mapping_labels_dat_as_binary() {
+        let dir = fresh_dir("extensions");
+        let mapping = dir.join("extensions.json");
+        std::fs::write(&mapping, r#"{".dat": "Binary", "md": "Text"}"#).unwrap();
+        let extensions = read_extensions(&mapping);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let config = DatasetConfig {
+            extensions: extensions.unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(config.file_type(Path::new("a.dat")), Some(FileType::Binary));
+        assert_eq!(config.file_type(Path::new("a.md")), Some(FileType::Text));
+        assert_eq!(config.file_type(Path::new("a.wav")), Some(FileType::Wav));
+        assert_eq!(config.file_type(Path::new("a.xyz")), None);
+    }
+
+    #[test]
+    fn second_collect_reads_from_the_cache() {
+        let dir = dataset_dir("cached", &[("txt", 4), ("wav", 2)]);
+        let cache_dir = fresh_dir("cache");
+        let config = DatasetConfig {
+            cache_dir: Some(cache_dir.clone()),
+            seed: Some(4),
+            ..Default::default()
+        };
+        let first = Dataset::collect(&dir, &config, &Device::Cpu).unwrap();
+        let second = Dataset::collect(&dir, &config, &Device::Cpu).unwrap();
+        let entries: Vec<PathBuf> = std::fs::read_dir(&cache_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        // Entries that no longer match the parsed files show the cache is read, not rebuilt.
+        let marker: Vec<u8> = [2f32; N_INPUT]
+            .iter()
+            .flat_map(|t| t.to_le_bytes())
+            .collect();
+        for entry in &entries {
+            std::fs::write(entry, &marker).unwrap();
+        }
+        let third = Dataset::collect(&dir, &config, &Device::Cpu);
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(&cache_dir).unwrap();
+        assert_eq!(entries.len(), 6);
+        assert_eq!(
+            first.train_inputs.to_vec2::<f32>().unwrap(),
+            second.train_inputs.to_vec2::<f32>().unwrap()
+        );
+        let third = third.unwrap().train_inputs.flatten_all().unwrap();
+        assert!(third.to_vec1::<f32>().unwrap().iter().all(|&t| t == 2.0));
+    }
+
+    #[test]
+    fn normalization_is_saved_with_the_model() {
+        let varmap = VarMap::new();
+        let vs = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        let config = NetworkConfig {
+            hidden: vec![4],
+            normalization: Normalization::Linear,
+            ..Default::default()
+        };
+        let network = Network::new(vs, &config).unwrap();
+        let model = temp_model("normalization");
+        network.save(&varmap, &model).unwrap();
+        let loaded = Network::load(&model, &Device::Cpu);
+        std::fs::remove_file(model).unwrap();
+        let mut loaded = loaded.unwrap();
+        assert_eq!(loaded.config.normalization, Normalization::Linear);
+
+        let mut table = BinaryTable::new();
+        table.parse(b"aaaaaaaab linear and log exports differ");
+        // The single "b " pair is 1/7 linearly but 0 in log space, route it to an output so both
+        // exports give different predictions.
+        loaded.layers = routing_network(&[(b' ' as usize * 256 + b'b' as usize, 0)]).layers;
+        let input = |norm| {
+            let input = table.export_with(norm);
+            Tensor::from_vec(input, (1, N_INPUT), &Device::Cpu).unwrap()
+        };
+        let softmax = |norm| {
+            let logits = loaded.forward(&input(norm), false).unwrap();
+            ops::softmax(&logits, D::Minus1)
+                .unwrap()
+                .to_vec2::<f32>()
+                .unwrap()[0]
+                .clone()
+        };
+        let probabilities = loaded.predict_probabilities(&table, &Device::Cpu).unwrap();
+        assert_eq!(probabilities.to_vec(), softmax(Normalization::Linear));
+        assert_ne!(probabilities.to_vec(), softmax(Normalization::Log));
+    }
+
+    #[test]
+    fn predict_batch_argmaxes_every_row() {
+        let network = routing_network(&[(0, 0), (1, 2), (2, 4), (3, 6)]);
+        let mut rows = vec![0f32; 3 * N_INPUT];
+        for (row, input) in [2, 0, 3].into_iter().enumerate() {
+            rows[row * N_INPUT + input] = 1.0;
+        }
+        let inputs = Tensor::from_vec(rows, (3, N_INPUT), &Device::Cpu).unwrap();
+        assert_eq!(
+            network.predict_batch(&inputs, &Device::Cpu).unwrap(),
+            [4, 0, 6]
+        );
+        let narrow = Tensor::zeros((3, 16), DType::F32, &Device::Cpu).unwrap();
+        assert!(matches!(
+            network.predict_batch(&narrow, &Device::Cpu),
+            Err(BvError::ShapeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn every_output_maps_to_a_class() {
+        let network = tiny_network();
+        let input = Tensor::zeros((1, N_INPUT), DType::F32, &Device::Cpu).unwrap();
+        let logits = network.forward(&input, false).unwrap();
+        assert_eq!(logits.dims(), [1, FileType::ALL.len()]);
+        for output in 0..N_OUTPUT as u32 {
+            let file_type = FileType::from_prediction(output).unwrap();
+            assert_eq!(file_type.output(), output);
+            assert_eq!(network.file_type(output as usize), Some(file_type));
+        }
+        assert_eq!(FileType::from_prediction(N_OUTPUT as u32), None);
+    }
+
+    #[test]
+    fn weight_decay_shrinks_the_weights() {
+        let l2_norm = |weight_decay| {
+            let config = TrainConfig {
+                weight_decay,
+                ..tiny_train_config(3)
+            };
+            let model = temp_model("weight-decay");
+            let dataset = contradicting_dataset("weight-decay");
+            let outcome = train(dataset, &model, &config, &Device::Cpu).unwrap();
+            _ = std::fs::remove_file(model);
+            outcome
+                .network
+                .layers
+                .iter()
+                .map(|layer| {
+                    let squares = layer.weight().sqr().unwrap().sum_all().unwrap();
+                    squares.to_scalar::<f32>().unwrap()
+                })
+                .sum::<f32>()
+        };
+        let plain = l2_norm(0.0);
+        let decayed = l2_norm(5.0);
+        assert!(decayed < plain, "{decayed} >= {plain}");
+    }
+
+    #[test]
+    fn inputs_shorter_than_two_bytes_are_too_short() {
+        let network = tiny_network();
+        for bytes in [&[][..], &[42][..]] {
+            let mut table = BinaryTable::new();
+            table.parse(bytes);
+            assert!(table.export().iter().all(|&t| t == 0.0));
+            assert!(matches!(
+                network.predict(&table, &Device::Cpu),
+                Err(BvError::InputTooShort)
+            ));
+        }
+    }
+
+    #[test]
+    fn cold_start_ignores_an_existing_model() {
+        let model = temp_model("cold-start");
+        let history = |warm_start, existing: bool| {
+            _ = std::fs::remove_file(&model);
+            if existing {
+                let (varmap, network) = tiny_network_with_weights();
+                network.save(&varmap, &model).unwrap();
+            }
+            let config = TrainConfig {
+                warm_start,
+                ..tiny_train_config(1)
+            };
+            let dataset = contradicting_dataset("cold-start");
+            train(dataset, &model, &config, &Device::Cpu)
+                .unwrap()
+                .history
+        };
+        let fresh = history(false, false);
+        let cold = history(false, true);
+        let warm = history(true, true);
+        _ = std::fs::remove_file(&model);
+        assert_eq!(cold, fresh);
+        assert_ne!(warm, fresh);
+    }
+
+    #[test]
+    fn history_has_an_entry_per_epoch_run() {
+        let run = |config: TrainConfig| {
+            let model = temp_model("history");
+            let dataset = contradicting_dataset("history");
+            let outcome = train(dataset, &model, &config, &Device::Cpu).unwrap();
+            _ = std::fs::remove_file(model);
+            outcome.history
+        };
+        let full = run(tiny_train_config(3));
+        let epochs: Vec<usize> = full.iter().map(|stats| stats.epoch).collect();
+        assert_eq!(epochs, [1, 2, 3]);
+
+        let stopped = run(TrainConfig {
+            patience: Some(1),
+            min_delta: 1e9,
+            ..tiny_train_config(10)
+        });
+        let epochs: Vec<usize> = stopped.iter().map(|stats| stats.epoch).collect();
+        assert_eq!(epochs, [1, 2]);
+    }
+
+    #[test]
+    fn scan_prediction_changes_at_the_boundary() {
+        let text = FileType::Text.output() as usize;
+        let binary = FileType::Binary.output() as usize;
+        // Runs of `a` only fill the `(a, a)` cell and runs of zeros only the `(0, 0)` cell.
+        let network = routing_network(&[(b'a' as usize * 257, text), (0, binary)]);
+        let mut bytes = vec![b'a'; 4000];
+        bytes.resize(8000, 0);
+        let results = network.scan(&bytes, 1000, 1000, &Device::Cpu).unwrap();
+        let types: Vec<(usize, FileType)> = results
+            .iter()
+            .map(|&(offset, typ, _)| (offset, typ))
+            .collect();
+        let mut expected = Vec::new();
+        for offset in (0..8000).step_by(1000) {
+            let typ = if offset < 4000 {
+                FileType::Text
+            } else {
+                FileType::Binary
+            };
+            expected.push((offset, typ));
+        }
+        assert_eq!(types, expected);
+    }
+
+    #[test]
+    fn new_classes_have_signatures_and_outputs() {
+        assert_eq!(FileType::from_magic(b"ID3\x04\x00"), Some(FileType::Mp3));
+        assert_eq!(
+            FileType::from_magic(b"\xFF\xFB\x90\x00"),
+            Some(FileType::Mp3)
+        );
+        assert_eq!(FileType::from_magic(b"GIF89a"), Some(FileType::Gif));
+        assert_eq!(
+            FileType::from_magic(b"PK\x03\x04\x14\x00"),
+            Some(FileType::Zip)
+        );
+        assert_eq!(FileType::from_path(Path::new("a.mp3")), Some(FileType::Mp3));
+        assert_eq!(FileType::from_path(Path::new("a.gif")), Some(FileType::Gif));
+        assert_eq!(FileType::from_path(Path::new("a.zip")), Some(FileType::Zip));
+
+        assert_eq!(N_OUTPUT, FileType::ALL.len());
+        let mut outputs: Vec<u32> = FileType::ALL.iter().map(|typ| typ.output()).collect();
+        outputs.sort();
+        assert_eq!(outputs, (0..N_OUTPUT as u32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn clipping_bounds_the_update() {
+        let step = |max_norm| {
+            let var = Var::zeros(4, DType::F32, &Device::Cpu).unwrap();
+            let mut optimizer = Optimizer::Sgd { lr: 1.0 }.build(vec![var.clone()]).unwrap();
+            // Every gradient is 1e6, so the unclipped update has a norm of 2e6.
+            let loss = var.as_tensor().affine(1e6, 0.0).unwrap().sum_all().unwrap();
+            optimizer
+                .backward_step(&loss, std::slice::from_ref(&var), max_norm)
+                .unwrap();
+            let update = var.as_tensor().sqr().unwrap().sum_all().unwrap();
+            update.to_scalar::<f32>().unwrap().sqrt()
+        };
+        assert_eq!(step(None), 2e6);
+        assert!((step(Some(1.0)) - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn unknown_class_is_encoded_and_predicted() {
+        let unknown = FileType::Unknown.output();
+        assert_eq!(unknown, N_OUTPUT as u32 - 1);
+        assert_eq!(FileType::from_prediction(unknown), Some(FileType::Unknown));
+        assert_eq!(
+            FileType::from_name(FileType::Unknown.name()),
+            Some(FileType::Unknown)
+        );
+
+        let config = DatasetConfig {
+            include_unknown: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            config.file_type(Path::new("a.xyz")),
+            Some(FileType::Unknown)
+        );
+        assert_eq!(DatasetConfig::default().file_type(Path::new("a.xyz")), None);
+
+        let network = constant_network(unknown as usize);
+        let mut table = BinaryTable::new();
+        table.parse(b"any input");
+        let probabilities = network.predict_probabilities(&table, &Device::Cpu).unwrap();
+        assert_eq!(
+            FileType::from_prediction_with_threshold(&probabilities, &network.classes, 0.5),
+            Some(FileType::Unknown)
+        );
+    }
+
+    #[test]
+    fn same_seed_initializes_the_same_weights() {
+        let first_layer = |seed| {
+            let (varmap, network) = tiny_network_with_weights();
+            seed_weights(&varmap, &mut seeded_rng(Some(seed))).unwrap();
+            network.layers[0].weight().to_vec2::<f32>().unwrap()
+        };
+        assert_eq!(first_layer(9), first_layer(9));
+        assert_ne!(first_layer(9), first_layer(10));
+    }
+
+    #[test]
+    fn valid_dir_becomes_the_test_set() {
+        let dir = dataset_dir("train-dir", &[("txt", 5), ("wav", 3)]);
+        let valid_dir = dataset_dir("valid-dir", &[("txt", 2), ("wav", 2), ("xyz", 1)]);
+        let config = DatasetConfig {
+            valid_dir: Some(valid_dir.clone()),
+            ..Default::default()
+        };
+        let dataset = Dataset::collect(&dir, &config, &Device::Cpu);
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(&valid_dir).unwrap();
+        let dataset = dataset.unwrap();
+        assert_eq!(dataset.train_inputs.dims2().unwrap(), (8, N_INPUT));
+        assert_eq!(dataset.test_inputs.dims2().unwrap(), (4, N_INPUT));
+    }
+
+    #[test]
+    fn step_schedule_decays_every_two_epochs() {
+        let schedule = LrSchedule::Step {
+            gamma: 0.1,
+            every: 2,
+        };
+        let rates: Vec<f64> = (1..=4)
+            .map(|epoch| schedule.learning_rate(LEARNING_RATE, epoch))
+            .collect();
+        let expected = [1.0, 1.0, 0.1, 0.1].map(|factor| LEARNING_RATE * factor);
+        for (rate, expected) in rates.iter().zip(expected) {
+            assert!((rate - expected).abs() < 1e-12, "{rates:?}");
+        }
+        let constant = LrSchedule::default().learning_rate(LEARNING_RATE, 4);
+        assert_eq!(constant, LEARNING_RATE);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn unreadable_files_are_counted_and_skipped() {
+        let dir = dataset_dir("unreadable", &[("txt", 3), ("wav", 3)]);
+        // Opening works, but reading from the unmapped address 0 fails with EIO, even as root.
+        std::os::unix::fs::symlink("/proc/self/mem", dir.join("mem.txt")).unwrap();
+        let dataset = Dataset::collect(&dir, &DatasetConfig::default(), &Device::Cpu);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let dataset = dataset.unwrap();
+        assert_eq!(dataset.unreadable, 1);
+        let len = dataset.train_outputs.dims1().unwrap() + dataset.test_outputs.dims1().unwrap();
+        assert_eq!(len, 6);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn evaluation_skips_unreadable_files() {
+        let dir = dataset_dir("evaluate-unreadable", &[("txt", 3)]);
+        std::os::unix::fs::symlink("/proc/self/mem", dir.join("mem.txt")).unwrap();
+        let network = constant_network(FileType::Text.output() as usize);
+        let evaluation = evaluate(&network, &dir, &DatasetConfig::default(), &Device::Cpu);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let evaluation = evaluation.unwrap();
+        assert_eq!(evaluation.unreadable, 1);
+        let text = FileType::Text.output() as usize;
+        assert_eq!(evaluation.confusion[text][text], 3);
+        assert_eq!(evaluation.accuracy(), 100.0);
+    }
+
+    #[test]
+    fn sample_cap_records_one_pair_less_than_the_cap() {
+        let dir = fresh_dir("sample-bytes");
+        let bytes: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+        for i in 0..2 {
+            std::fs::write(dir.join(format!("{i}.bin")), &bytes).unwrap();
+        }
+        let config = DatasetConfig {
+            sample_bytes: Some(100),
+            normalization: Normalization::None,
+            ..Default::default()
+        };
+        let dataset = Dataset::collect(&dir, &config, &Device::Cpu);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let pairs = dataset.unwrap().train_inputs.sum_all().unwrap();
+        assert_eq!(pairs.to_scalar::<f32>().unwrap(), 99.0);
+
+        let varmap = VarMap::new();
+        let vs = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        let config = NetworkConfig {
+            hidden: vec![4],
+            sample_bytes: Some(100),
+            ..Default::default()
+        };
+        let network = Network::new(vs, &config).unwrap();
+        let mut table = BinaryTable::new();
+        table.parse(network.sample(&bytes));
+        assert_eq!(table.stats().transitions, 99);
+    }
+
+    #[test]
+    fn outcome_reports_the_training_device() {
+        let model = temp_model("device");
+        let dataset = contradicting_dataset("device");
+        let outcome = train(dataset, &model, &tiny_train_config(1), &Device::Cpu).unwrap();
+        _ = std::fs::remove_file(model);
+        assert_eq!(outcome.device, device_name(&Device::Cpu));
+        assert_eq!(outcome.device, "cpu");
+        assert_eq!(outcome.dtype, DType::F32);
+        assert_eq!(outcome.input_len, N_INPUT);
+        assert_eq!((outcome.train_len, outcome.test_len), (8, 8));
+    }
+
+    #[test]
+    fn min_accuracy_is_the_success_threshold() {
+        let run = |min_accuracy| {
+            let config = TrainConfig {
+                min_accuracy,
+                ..tiny_train_config(1)
+            };
+            let model = temp_model("threshold");
+            let result = train(
+                contradicting_dataset("threshold"),
+                &model,
+                &config,
+                &Device::Cpu,
+            );
+            _ = std::fs::remove_file(model);
+            result
+        };
+        let reached = run(0.0).unwrap().history[0].test_accuracy;
+        assert!(run(reached).is_ok(), "{reached}");
+        assert!(matches!(
+            run(reached + 1.0),
+            Err(BvError::ModelNotConverged { accuracy }) if accuracy == reached
+        ));
+    }
+
+    #[test]
+    fn ensemble_of_identical_models_agrees_with_one() {
+        let (varmap, network) = tiny_network_with_weights();
+        let models = [temp_model("ensemble-a"), temp_model("ensemble-b")];
+        for model in &models {
+            network.save(&varmap, model).unwrap();
+        }
+        let ensemble = Ensemble::load(&models, &Device::Cpu);
+        for model in &models {
+            std::fs::remove_file(model).unwrap();
+        }
+        let ensemble = ensemble.unwrap();
+        let mut table = BinaryTable::new();
+        table.parse(b"both members see the same input");
+        let single = network.predict_probabilities(&table, &Device::Cpu).unwrap();
+        let averaged = ensemble.predict(&table, &Device::Cpu).unwrap();
+        assert_eq!(argmax(&averaged).0, argmax(&single).0);
+        for (a, b) in averaged.iter().zip(single) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn excluded_and_hidden_directories_are_not_collected() {
+        let dir = dataset_dir("filter", &[("txt", 2)]);
+        for subdir in ["skip", ".git"] {
+            std::fs::create_dir(dir.join(subdir)).unwrap();
+            write_files(&dir.join(subdir), "txt", 3, true);
+        }
+        let collected = |filter: &PathFilter| {
+            let mut files = Vec::new();
+            walk_dir(&dir, false, filter, &mut |path| {
+                files.push(path);
+                Ok(())
+            })
+            .unwrap();
+            files.len()
+        };
+        let exclude = PathFilter {
+            exclude: vec![Pattern::new("skip/**").unwrap()],
+            ..Default::default()
+        };
+        let hidden = PathFilter {
+            hidden: true,
+            ..Default::default()
+        };
+        let counts = (
+            collected(&PathFilter::default()),
+            collected(&exclude),
+            collected(&hidden),
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(counts, (5, 2, 8));
+    }
+
+    #[test]
+    fn report_f1_matches_hand_computed_values() {
+        let confusion = vec![vec![8, 2, 0], vec![1, 5, 4], vec![0, 0, 0]];
+        let report = classification_report(&confusion);
+        let close = |a: f32, b: f32| assert!((a - b).abs() < 1e-6, "{a} != {b}");
+        // Precision 8/9 and recall 8/10.
+        close(report.classes[0].precision, 8.0 / 9.0);
+        close(report.classes[0].recall, 0.8);
+        close(report.classes[0].f1, 16.0 / 19.0);
+        // Precision 5/7 and recall 5/10.
+        close(report.classes[1].f1, 10.0 / 17.0);
+        // Never true but predicted four times.
+        close(report.classes[2].f1, 0.0);
+        assert_eq!(report.classes[2].support, 0);
+        close(report.macro_avg.f1, (16.0 / 19.0 + 10.0 / 17.0) / 3.0);
+        close(report.weighted_avg.f1, (16.0 / 19.0 + 10.0 / 17.0) / 2.0);
+        assert_eq!(report.weighted_avg.support, 20);
+    }
+
+    #[test]
+    fn half_precision_predicts_finite_probabilities() {
+        let network = tiny_network().to_dtype(DType::F16, &Device::Cpu).unwrap();
+        assert_eq!(network.dtype, DType::F16);
+        let mut table = BinaryTable::new();
+        table.parse(b"a known input for the half precision network");
+        let probabilities = network.predict_probabilities(&table, &Device::Cpu).unwrap();
+        assert!(probabilities.iter().all(|p| p.is_finite()));
+        assert!((probabilities.iter().sum::<f32>() - 1.0).abs() < 1e-2);
+    }
+
+    #[test]
+    fn extensionless_files_are_labeled_by_content() {
+        let dir = fresh_dir("extensionless");
+        std::fs::write(dir.join("README"), "Ünïcode text with\nseveral lines\n").unwrap();
+        let random: Vec<u8> = (0..512u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        std::fs::write(dir.join("blob"), random).unwrap();
+        let config = DatasetConfig {
+            sniff_extensionless: true,
+            ..Default::default()
+        };
+        let mut table = BinaryTable::new();
+        let mut label = |name| {
+            let file = read_file(&mut table, &dir.join(name), &config).unwrap();
+            file.map(|(file_type, _)| file_type)
+        };
+        let labels = (label("README"), label("blob"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(labels, (Some(FileType::Text), Some(FileType::Binary)));
+    }
+
+    #[test]
+    fn wrong_width_inputs_are_shape_mismatches() {
+        let network = tiny_network();
+        let narrow = Tensor::zeros((2, 100), DType::F32, &Device::Cpu).unwrap();
+        match network.forward(&narrow, false) {
+            Err(BvError::ShapeMismatch { got, .. }) => assert_eq!(got, [2, 100]),
+            result => panic!("expected a shape mismatch, got {result:?}"),
+        }
+        let flat = Tensor::zeros(N_INPUT, DType::F32, &Device::Cpu).unwrap();
+        assert!(matches!(
+            network.predict_batch(&flat, &Device::Cpu),
+            Err(BvError::ShapeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn mismatched_class_scheme_is_refused() {
+        let model = save_with_metadata("scheme", CLASS_SCHEME_METADATA_KEY, "one-hot:5");
+        let result = Network::load(&model, &Device::Cpu);
+        std::fs::remove_file(&model).unwrap();
+        match result {
+            Err(BvError::InvalidModel(message)) => assert!(message.contains("one-hot:5")),
+            other => panic!("expected InvalidModel, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn checkpointing_writes_one_file_per_epoch() {
+        let checkpoints = |name: &str, keep_best: bool| {
+            let dataset = contradicting_dataset(&format!("{name}-data"));
+            let dir = fresh_dir(name);
+            let model = temp_model(name);
+            let config = TrainConfig {
+                checkpoint_dir: Some(dir.join("checkpoints")),
+                keep_best,
+                ..tiny_train_config(3)
+            };
+            let outcome = train(dataset, &model, &config, &Device::Cpu).unwrap();
+            assert_eq!(outcome.history.len(), 3);
+            let mut epochs: Vec<usize> = std::fs::read_dir(dir.join("checkpoints"))
+                .unwrap()
+                .map(|entry| {
+                    let name = entry.unwrap().file_name().into_string().unwrap();
+                    let epoch = name
+                        .strip_prefix("epoch_")
+                        .and_then(|name| name.strip_suffix(".safetensors"))
+                        .unwrap();
+                    epoch.parse().unwrap()
+                })
+                .collect();
+            epochs.sort();
+            std::fs::remove_dir_all(dir).unwrap();
+            std::fs::remove_file(model).unwrap();
+            epochs
+        };
+        assert_eq!(checkpoints("checkpoints", false), [1, 2, 3]);
+        assert_eq!(checkpoints("keep-best", true).len(), 1);
+    }
+
+    #[test]
+    fn loading_a_directory_or_garbage_names_the_cause() {
+        let dir = fresh_dir("load-dir");
+        let garbage = dir.join("garbage.safetensors");
+        std::fs::write(&garbage, b"not a safetensors file").unwrap();
+        let cause = |path: &Path| match Network::load(path, &Device::Cpu) {
+            Err(BvError::InvalidModelFile {
+                path: reported,
+                source,
+            }) => {
+                assert_eq!(reported, path);
+                source
+            }
+            other => panic!("expected InvalidModelFile, got {:?}", other.err()),
+        };
+        assert!(matches!(cause(&dir), ModelFileError::NotAFile));
+        assert!(matches!(cause(&garbage), ModelFileError::Parse(_)));
+        assert!(matches!(
+            cause(&dir.join("missing.safetensors")),
+            ModelFileError::NotFound
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn resuming_continues_from_the_saved_weights() {
+        let model = temp_model("resume");
+        let dir = dataset_dir("resume", &[("txt", 8), ("wav", 8)]);
+        let run = |resume_from: Option<PathBuf>, hidden: usize| {
+            let config = TrainConfig {
+                network: NetworkConfig {
+                    hidden: vec![hidden],
+                    ..Default::default()
+                },
+                resume_from,
+                ..tiny_train_config(2)
+            };
+            let dataset = Dataset::collect(
+                &dir,
+                &DatasetConfig {
+                    seed: Some(1),
+                    ..Default::default()
+                },
+                &Device::Cpu,
+            )
+            .unwrap();
+            train(dataset, &model, &config, &Device::Cpu).map(|outcome| outcome.history)
+        };
+        let first = run(None, 4).unwrap();
+        let resumed = run(Some(model.clone()), 4).unwrap();
+        let mismatched = run(Some(model.clone()), 8);
+        _ = std::fs::remove_file(&model);
+        std::fs::remove_dir_all(dir).unwrap();
+        assert!(resumed[0].test_loss < first[0].test_loss);
+        assert!(resumed.last().unwrap().test_accuracy >= first.last().unwrap().test_accuracy);
+        assert!(matches!(mismatched, Err(BvError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn info_reports_layers_and_classes_of_a_saved_model() {
+        let (varmap, mut network) = tiny_network_with_weights();
+        network.classes.reverse();
+        let model = temp_model("info");
+        network.save(&varmap, &model).unwrap();
+        let info = ModelInfo::read(&model);
+        std::fs::remove_file(model).unwrap();
+        let info = info.unwrap();
+        assert_eq!(info.layers, [N_INPUT, 4, N_OUTPUT]);
+        let classes: Vec<&str> = FileType::ALL.iter().rev().map(|typ| typ.name()).collect();
+        assert_eq!(info.classes.unwrap(), classes);
+        assert_eq!(info.class_scheme.as_deref(), Some(CLASS_SCHEME));
+        assert_eq!(info.version.as_deref(), Some(env!("CARGO_PKG_VERSION")));
+        assert_eq!(info.parameters, N_INPUT * 4 + 4 + 4 * N_OUTPUT + N_OUTPUT);
+    }
+
+    #[test]
+    fn evaluation_labels_files_like_training() {
+        let dir = dataset_dir("evaluate-config", &[("dat", 2), ("txt", 3)]);
+        let network = constant_network(FileType::Binary.output() as usize);
+        let config = DatasetConfig {
+            extensions: HashMap::from([("dat".to_string(), FileType::Binary)]),
+            filter: PathFilter {
+                exclude: vec![Pattern::new("*.txt").unwrap()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let evaluation = evaluate(&network, &dir, &config, &Device::Cpu);
+        let unconfigured = evaluate(&network, &dir, &DatasetConfig::default(), &Device::Cpu);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let evaluation = evaluation.unwrap();
+        let binary = FileType::Binary.output() as usize;
+        assert_eq!(evaluation.skipped, 0);
+        assert_eq!(evaluation.confusion[binary][binary], 2);
+        assert_eq!(evaluation.accuracy(), 100.0);
+        let unconfigured = unconfigured.unwrap();
+        assert_eq!(unconfigured.skipped, 2);
+        assert_eq!(unconfigured.accuracy(), 0.0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn failed_cache_writes_keep_every_file() {
+        let dir = dataset_dir("uncacheable", &[("txt", 3), ("wav", 3)]);
+        // Nobody can create files in /proc, not even root.
+        let config = DatasetConfig {
+            cache_dir: Some(PathBuf::from("/proc")),
+            seed: Some(4),
+            ..Default::default()
+        };
+        let cached = Dataset::collect(&dir, &config, &Device::Cpu);
+        let plain = Dataset::collect(
+            &dir,
+            &DatasetConfig {
+                cache_dir: None,
+                ..config
+            },
+            &Device::Cpu,
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        let (cached, plain) = (cached.unwrap(), plain.unwrap());
+        assert_eq!(cached.unreadable, 0);
+        assert_eq!(
+            sorted_rows(&[&cached.train_inputs, &cached.test_inputs]),
+            sorted_rows(&[&plain.train_inputs, &plain.test_inputs])
+        );
+    }
+
+    #[test]
+    fn missing_metadata_decodes_to_the_defaults() {
+        let stored = decode_metadata(&HashMap::new()).unwrap();
+        assert_eq!(stored.config, NetworkConfig::default());
+        assert!(stored.version.is_none() && stored.architecture.is_none());
+        assert!(stored.classes.is_none() && stored.class_scheme.is_none());
+
+        let metadata = HashMap::from([
+            ("layers".to_string(), "16,2,9".to_string()),
+            ("normalization".to_string(), "linear".to_string()),
+            ("sample_bytes".to_string(), "64".to_string()),
+            ("classes".to_string(), "Text,Wav".to_string()),
+        ]);
+        let stored = decode_metadata(&metadata).unwrap();
+        assert_eq!(stored.config.sizes(), [16, 2, 9]);
+        assert_eq!(stored.config.normalization, Normalization::Linear);
+        assert_eq!(stored.config.sample_bytes, Some(64));
+        assert_eq!(stored.classes.unwrap(), ["Text", "Wav"]);
+
+        let invalid = HashMap::from([("sample_bytes".to_string(), "many".to_string())]);
+        assert!(matches!(
+            decode_metadata(&invalid),
+            Err(BvError::InvalidModel(_))
+        ));
     }
 }
@@ -0,0 +1,45 @@
+//! Python bindings, built as an extension module with
+//! `cargo rustc --release --lib --features extension-module --crate-type cdylib`.
+
+use std::path::PathBuf;
+
+use candle::Device;
+use pyo3::{exceptions::PyRuntimeError, prelude::*};
+
+use crate::table::BinaryTable;
+
+/// Parses `bytes` and returns the log-normalized 256×256 table, row by row.
+#[pyfunction]
+fn parse_and_export(bytes: &[u8]) -> Vec<f32> {
+    let mut table = BinaryTable::new();
+    table.parse(bytes);
+    table.export()
+}
+
+/// Returns the name of the most likely file type of `bytes` and its probability.
+#[pyfunction]
+fn classify(model_path: PathBuf, bytes: &[u8]) -> PyResult<(String, f32)> {
+    let (file_type, confidence) = crate::classify(model_path, bytes, &Device::Cpu)
+        .map_err(|err| PyRuntimeError::new_err(err.to_string()))?;
+    Ok((file_type.name().to_string(), confidence))
+}
+
+#[pymodule]
+fn binary_visualizer(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(parse_and_export, m)?)?;
+    m.add_function(wrap_pyfunction!(classify, m)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exported_functions_return_tables_and_errors() {
+        let export = parse_and_export(b"abab");
+        assert_eq!(export.len(), 256 * 256);
+        assert_eq!(export[b'b' as usize * 256 + b'a' as usize], 1.0);
+        assert!(classify(PathBuf::from("missing.safetensors"), b"ab").is_err());
+    }
+}
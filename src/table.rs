@@ -1,58 +1,1184 @@
+use std::{
+    io::{ErrorKind, Read, Write},
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::{
+    colormap::Colormap,
+    error::{BvError, Result},
+};
+use image::{Rgb, RgbImage};
+
+const MAGIC: &[u8; 4] = b"BVT1";
+/// Like `MAGIC`, followed by a byte holding the resolution in bits.
+const MAGIC_WITH_BITS: &[u8; 4] = b"BVT2";
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Normalization {
+    #[default]
+    Log,
+    Linear,
+    None,
+}
+
+impl Normalization {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Log => "log",
+            Self::Linear => "linear",
+            Self::None => "none",
+        }
+    }
+}
+
+impl FromStr for Normalization {
+    type Err = BvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "log" => Ok(Self::Log),
+            "linear" => Ok(Self::Linear),
+            "none" => Ok(Self::None),
+            _ => Err(BvError::InvalidConfig(format!(
+                "Unknown normalization '{s}'"
+            ))),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TableStats {
+    pub transitions: u64,
+    pub occupied: usize,
+    /// Shannon entropy of the byte-pair distribution in bits.
+    pub entropy: f32,
+    /// The most frequent `(first, second)` byte pair and its count.
+    pub most_frequent: Option<((u8, u8), u32)>,
+}
+
+/// How `BinaryTable::distance` compares two exports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Metric {
+    /// One minus the cosine similarity, between 0 for the same distribution and 1.
+    Cosine,
+    /// The Euclidean distance.
+    L2,
+}
+
+/// Which counts `BinaryTable::export_normalized` normalizes every cell against.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Axis {
+    /// The largest count of the whole table, like `export`.
+    #[default]
+    Global,
+    /// The largest count of the cell's row, i.e. of all pairs with the same second byte.
+    Row,
+    /// The largest count of the cell's column, i.e. of all pairs with the same first byte.
+    Col,
+}
+
+/// What an export was computed with: the normalization and whether the `(0, 0)` pair was masked.
+type ExportKey = (Normalization, bool);
+
 pub struct BinaryTable {
-    pub max: f32,
-    pub dots: Box<[[u32; 256]; 256]>,
+    /// Indexed as `dots[y][x]`, read and written through `count_at` and `set_count`.
+    dots: Box<[[u32; 256]; 256]>,
+    /// Only the top `bits` bits of every byte are recorded, so just the top left
+    /// `size()`×`size()` cells of `dots` are used.
+    bits: u8,
+    /// The last export, dropped by every method that changes the counts.
+    export_cache: Mutex<Option<(ExportKey, Arc<[f32]>)>>,
+    /// How often the export was computed instead of taken from the cache.
+    #[cfg(test)]
+    computed_exports: std::sync::atomic::AtomicUsize,
 }
 
 impl BinaryTable {
     pub fn new() -> Self {
         Self {
-            max: 0.0,
             dots: Box::new([[0; 256]; 256]),
+            bits: 8,
+            export_cache: Mutex::new(None),
+            #[cfg(test)]
+            computed_exports: Default::default(),
         }
     }
 
+    /// Creates a coarser table with a `2^bits`×`2^bits` grid, e.g. 64×64 for 6 bits.
+    pub fn with_bits(bits: u8) -> Result<Self> {
+        if !(1..=8).contains(&bits) {
+            return Err(BvError::InvalidConfig(format!(
+                "Resolution of {bits} bits is not between 1 and 8"
+            )));
+        }
+        Ok(Self {
+            bits,
+            ..Self::new()
+        })
+    }
+
+    /// Creates a full resolution table from precomputed counts, indexed like `dots`.
+    pub fn from_counts(counts: [[u32; 256]; 256]) -> Self {
+        Self {
+            dots: Box::new(counts),
+            ..Self::new()
+        }
+    }
+
+    /// How often byte `x` was followed by byte `y`.
+    pub fn count_at(&self, x: u8, y: u8) -> u32 {
+        self.dots[y as usize][x as usize]
+    }
+
+    pub fn set_count(&mut self, x: u8, y: u8, count: u32) {
+        self.invalidate();
+        self.dots[y as usize][x as usize] = count;
+    }
+
+    /// The number of top bits recorded of every byte, between 1 and 8.
+    pub fn bits(&self) -> u8 {
+        self.bits
+    }
+
+    /// The width and height of the grid.
+    pub fn size(&self) -> usize {
+        1 << self.bits
+    }
+
     pub fn clear(&mut self) {
-        self.max = 0.0;
+        self.invalidate();
         for dots in self.dots.iter_mut() {
             dots.fill(0);
         }
     }
 
+    /// Whether no byte pair has been recorded, e.g. after parsing fewer than two bytes.
+    pub fn is_empty(&self) -> bool {
+        self.dots.iter().flatten().all(|&value| value == 0)
+    }
+
     pub fn parse(&mut self, bytes: &[u8]) {
-        for window in bytes.windows(2) {
-            let xb = window[0];
-            let yb = window[1];
+        self.parse_with_stride(bytes, 1);
+    }
+
+    /// Records every byte pair read from `reader` without loading it into memory at once.
+    ///
+    /// The last byte of every chunk is carried over, so pairs spanning two chunks are recorded
+    /// exactly like `parse` would.
+    pub fn parse_reader<R>(&mut self, mut reader: R) -> std::io::Result<()>
+    where
+        R: Read,
+    {
+        let mut buffer = vec![0u8; CHUNK_SIZE + 1];
+        let mut carry = 0;
+        loop {
+            let read = match reader.read(&mut buffer[carry..]) {
+                Ok(0) => return Ok(()),
+                Ok(read) => read,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            let len = carry + read;
+            self.parse(&buffer[..len]);
+            buffer[0] = buffer[len - 1];
+            carry = 1;
+        }
+    }
+
+    /// Records every `stride`-th byte pair of `bytes`.
+    ///
+    /// Parsing several inputs into the same table accumulates their counts. A stride of zero or
+    /// one larger than the input records nothing.
+    pub fn parse_with_stride(&mut self, bytes: &[u8], stride: usize) {
+        if stride == 0 || stride > bytes.len() {
+            return;
+        }
+        self.invalidate();
+        let shift = 8 - self.bits;
+        for window in bytes.windows(2).step_by(stride) {
+            let xb = window[0] >> shift;
+            let yb = window[1] >> shift;
             let x = xb as usize;
             let y = yb as usize;
             let value = self.dots[y][x].saturating_add(1);
             self.dots[y][x] = value;
-            if value > 0 {
-                let f = (value as f32).ln();
-                if f > self.max {
-                    self.max = f;
+        }
+    }
+
+    /// Adds the counts of `other` to this table, e.g. to build the fingerprint of a whole class
+    /// of files. Both tables must have the same resolution.
+    pub fn merge(&mut self, other: &BinaryTable) {
+        assert_eq!(self.bits, other.bits, "Tables have different resolutions");
+        self.invalidate();
+        for (dot, &value) in self
+            .dots
+            .iter_mut()
+            .flatten()
+            .zip(other.dots.iter().flatten())
+        {
+            *dot = dot.saturating_add(value);
+        }
+    }
+
+    /// A copy with the `(0, 0)` cell cleared, for the exports that have no `mask_zero_pair`
+    /// option.
+    pub fn without_zero_pair(&self) -> Self {
+        let mut dots = self.dots.clone();
+        dots[0][0] = 0;
+        Self {
+            dots,
+            bits: self.bits,
+            ..Self::new()
+        }
+    }
+
+    fn invalidate(&mut self) {
+        *self
+            .export_cache
+            .get_mut()
+            .expect("Export cache lock poisoned") = None;
+    }
+
+    /// The natural logarithm of the largest count, or zero for an empty table. Computed on
+    /// demand, so `parse` only has to count.
+    pub fn max(&self) -> f32 {
+        max_ln(self.dots.iter().flatten())
+    }
+
+    pub fn export(&self) -> Vec<f32> {
+        self.export_with(Normalization::Log)
+    }
+
+    /// Copies the cached export, computing and caching it first if the counts changed since.
+    pub fn export_with(&self, norm: Normalization) -> Vec<f32> {
+        self.export_masked(norm, false)
+    }
+
+    /// Like `export_with`, but with `mask_zero_pair` the `(0, 0)` cell, which dominates files
+    /// with long runs of zero bytes, exports as 0 and the remaining cells are normalized against
+    /// their own maximum. The counts are left alone.
+    pub fn export_masked(&self, norm: Normalization, mask_zero_pair: bool) -> Vec<f32> {
+        self.cached_export((norm, mask_zero_pair)).to_vec()
+    }
+
+    /// Like `export_with`, but shares the cached export instead of copying it. The export is
+    /// only recomputed after the counts changed or for a different normalization.
+    pub fn export_shared(&self, norm: Normalization) -> Arc<[f32]> {
+        self.cached_export((norm, false))
+    }
+
+    fn cached_export(&self, key: ExportKey) -> Arc<[f32]> {
+        let mut cache = self.lock_cache();
+        if let Some((cached, export)) = &*cache {
+            if *cached == key {
+                return export.clone();
+            }
+        }
+        let export: Arc<[f32]> = self.compute_export(key).into();
+        *cache = Some((key, export.clone()));
+        export
+    }
+
+    fn lock_cache(&self) -> MutexGuard<'_, Option<(ExportKey, Arc<[f32]>)>> {
+        self.export_cache
+            .lock()
+            .expect("Export cache lock poisoned")
+    }
+
+    fn compute_export(&self, (norm, mask_zero_pair): ExportKey) -> Vec<f32> {
+        #[cfg(test)]
+        self.computed_exports
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let mut tensor = vec![0f32; self.size() * self.size()];
+        // `(0, 0)` is the first cell, so skipping it masks the pair.
+        let counts = || self.dots.iter().flatten().skip(mask_zero_pair as usize);
+        match norm {
+            Normalization::Log => {
+                let max = max_ln(counts());
+                if max <= 0.0 {
+                    return tensor;
                 }
+                self.fill(&mut tensor, |value| (value as f32).ln() / max);
             }
+            Normalization::Linear => {
+                let max = counts().copied().max().unwrap_or(0);
+                if max == 0 {
+                    return tensor;
+                }
+                self.fill(&mut tensor, |value| value as f32 / max as f32);
+            }
+            Normalization::None => self.fill(&mut tensor, |value| value as f32),
         }
+        if mask_zero_pair {
+            tensor[0] = 0.0;
+        }
+        tensor
     }
 
-    pub fn export(&self) -> Vec<f32> {
-        let mut tensor = vec![0f32; 256 * 256];
+    /// Log-normalizes against the `percentile`-th (0 to 100) nonzero count instead of the maximum,
+    /// so a few very frequent pairs do not darken everything else. Cells above it are clamped.
+    pub fn export_clipped(&self, percentile: f32) -> Vec<f32> {
+        let mut counts: Vec<u32> = self
+            .dots
+            .iter()
+            .flatten()
+            .copied()
+            .filter(|&value| value > 0)
+            .collect();
+        let mut tensor = vec![0f32; self.size() * self.size()];
+        if counts.is_empty() {
+            return tensor;
+        }
+        counts.sort_unstable();
+        let rank = (percentile.clamp(0.0, 100.0) / 100.0 * (counts.len() - 1) as f32).round();
+        let max = (counts[rank as usize] as f32).ln();
+        if max <= 0.0 {
+            self.fill(&mut tensor, |_| 1.0);
+        } else {
+            self.fill(&mut tensor, |value| ((value as f32).ln() / max).min(1.0));
+        }
+        tensor
+    }
+
+    /// Maps every nonzero cell to the fraction of nonzero cells with at most its count, so the
+    /// brightness is spread evenly over `(0, 1]` no matter how skewed the counts are.
+    pub fn export_equalized(&self) -> Vec<f32> {
+        let mut counts: Vec<u32> = self
+            .dots
+            .iter()
+            .flatten()
+            .copied()
+            .filter(|&value| value > 0)
+            .collect();
+        counts.sort_unstable();
+        let mut tensor = vec![0f32; self.size() * self.size()];
+        let len = counts.len() as f32;
+        self.fill(&mut tensor, |value| {
+            counts.partition_point(|&count| count <= value) as f32 / len
+        });
+        tensor
+    }
+
+    /// Log-normalizes every cell against the largest count along `axis`, so bands with few
+    /// transitions are not darkened by the dominant ones. The largest cell of every nonempty
+    /// row or column becomes 1.
+    pub fn export_normalized(&self, axis: Axis) -> Vec<f32> {
+        let size = self.size();
+        let band_max = |band: usize| {
+            (0..size)
+                .map(|i| match axis {
+                    Axis::Row => self.dots[band][i],
+                    _ => self.dots[i][band],
+                })
+                .max()
+                .unwrap_or(0)
+        };
+        let maxima: Vec<u32> = match axis {
+            Axis::Global => return self.export(),
+            Axis::Row | Axis::Col => (0..size).map(band_max).collect(),
+        };
+        let mut tensor = vec![0f32; size * size];
+        for y in 0..size {
+            for x in 0..size {
+                let value = self.dots[y][x];
+                let max = maxima[if axis == Axis::Row { y } else { x }];
+                tensor[y * size + x] = match (value, max) {
+                    (0, _) => 0.0,
+                    (_, 1) => 1.0,
+                    _ => (value as f32).ln() / (max as f32).ln(),
+                };
+            }
+        }
+        tensor
+    }
+
+    /// Returns the per-cell difference of both normalized exports, in `[-1, 1]`. Positive values
+    /// mark transitions that are more frequent in `self`. Both tables must have the same
+    /// resolution.
+    pub fn diff(&self, other: &BinaryTable) -> Vec<f32> {
+        assert_eq!(self.bits, other.bits, "Tables have different resolutions");
+        self.export()
+            .into_iter()
+            .zip(other.export())
+            .map(|(a, b)| a - b)
+            .collect()
+    }
+
+    /// Compares the log-normalized exports of both tables, e.g. to find similar files. Empty
+    /// tables are at a cosine distance of 0 to each other and 1 to everything else. Both tables
+    /// must have the same resolution.
+    pub fn distance(&self, other: &BinaryTable, metric: Metric) -> f32 {
+        assert_eq!(self.bits, other.bits, "Tables have different resolutions");
+        let (a, b) = (
+            self.export_shared(Normalization::Log),
+            other.export_shared(Normalization::Log),
+        );
+        match metric {
+            Metric::Cosine => {
+                let dot: f32 = a.iter().zip(b.iter()).map(|(a, b)| a * b).sum();
+                let norm_a = a.iter().map(|a| a * a).sum::<f32>().sqrt();
+                let norm_b = b.iter().map(|b| b * b).sum::<f32>().sqrt();
+                let similarity = match (norm_a > 0.0, norm_b > 0.0) {
+                    (true, true) => dot / (norm_a * norm_b),
+                    (false, false) => 1.0,
+                    _ => 0.0,
+                };
+                (1.0 - similarity).clamp(0.0, 1.0)
+            }
+            Metric::L2 => a
+                .iter()
+                .zip(b.iter())
+                .map(|(a, b)| (a - b) * (a - b))
+                .sum::<f32>()
+                .sqrt(),
+        }
+    }
+
+    fn fill<F>(&self, tensor: &mut [f32], f: F)
+    where
+        F: Fn(u32) -> f32,
+    {
+        let size = self.size();
+        for (y, row) in self.dots.iter().take(size).enumerate() {
+            for (x, &value) in row.iter().take(size).enumerate() {
+                let t = if value == 0 { 0.0 } else { f(value) };
+                tensor[y * size + x] = t;
+            }
+        }
+    }
+
+    /// Averages each `factor`×`factor` block of the exported table into a
+    /// `(size / factor)`×`(size / factor)` grid.
+    pub fn downsample(&self, factor: usize) -> Result<Vec<f32>> {
+        let full = self.size();
+        if factor == 0 || full % factor != 0 {
+            return Err(BvError::InvalidConfig(format!(
+                "Factor {factor} does not divide {full}"
+            )));
+        }
+        let export = self.export();
+        let size = full / factor;
+        let mut coarse = vec![0f32; size * size];
+        for (i, &t) in export.iter().enumerate() {
+            let (x, y) = (i % full, i / full);
+            coarse[(y / factor) * size + x / factor] += t;
+        }
+        let cells = (factor * factor) as f32;
+        for t in coarse.iter_mut() {
+            *t /= cells;
+        }
+        Ok(coarse)
+    }
+
+    pub fn stats(&self) -> TableStats {
+        let mut transitions = 0u64;
+        let mut occupied = 0;
+        let mut most_frequent = None;
         for (y, row) in self.dots.iter().enumerate() {
             for (x, &value) in row.iter().enumerate() {
-                let t = if value == 0 {
-                    0.0
+                if value == 0 {
+                    continue;
+                }
+                transitions += value as u64;
+                occupied += 1;
+                if most_frequent.is_none_or(|(_, count)| value > count) {
+                    most_frequent = Some(((x as u8, y as u8), value));
+                }
+            }
+        }
+        let mut entropy = 0.0;
+        if transitions > 0 {
+            for &value in self.dots.iter().flatten().filter(|&&value| value > 0) {
+                let p = value as f64 / transitions as f64;
+                entropy -= p * p.log2();
+            }
+        }
+        TableStats {
+            transitions,
+            occupied,
+            entropy: entropy as f32,
+            most_frequent,
+        }
+    }
+
+    pub fn write_to<W>(&self, mut writer: W) -> Result<()>
+    where
+        W: Write,
+    {
+        if self.bits == 8 {
+            writer.write_all(MAGIC)?;
+        } else {
+            writer.write_all(MAGIC_WITH_BITS)?;
+            writer.write_all(&[self.bits])?;
+        }
+        writer.write_all(&self.max().to_le_bytes())?;
+        for &value in self.dots.iter().flatten() {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes the raw counts as CSV with one line per second byte and one column per first byte.
+    pub fn write_csv<W>(&self, mut writer: W) -> Result<()>
+    where
+        W: Write,
+    {
+        let size = self.size();
+        for row in self.dots.iter().take(size) {
+            let line = row[..size]
+                .iter()
+                .map(|value| value.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            writeln!(writer, "{line}")?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn read_from<R>(mut reader: R) -> Result<Self>
+    where
+        R: Read,
+    {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let (bits, header) = match bytes.get(..MAGIC.len() + 1) {
+            Some([magic @ .., _]) if magic == MAGIC => (8, MAGIC.len()),
+            Some([magic @ .., bits]) if magic == MAGIC_WITH_BITS => (*bits, MAGIC.len() + 1),
+            _ => return Err(BvError::InvalidTable("wrong magic".to_string())),
+        };
+        let expected = header + 4 + 256 * 256 * 4;
+        if bytes.len() != expected {
+            return Err(BvError::InvalidTable(format!(
+                "{} bytes, expected {expected}",
+                bytes.len()
+            )));
+        }
+        let (max, rest) = bytes[header..].split_at(4);
+        let mut table = Self::with_bits(bits)
+            .map_err(|_| BvError::InvalidTable(format!("invalid resolution {bits}")))?;
+        // The stored max is only kept for compatibility, it is derived from the counts.
+        let max = f32::from_le_bytes(max.try_into().expect("Split at 4 bytes"));
+        for (dot, chunk) in table.dots.iter_mut().flatten().zip(rest.chunks_exact(4)) {
+            *dot = u32::from_le_bytes(chunk.try_into().expect("Chunks of 4 bytes"));
+        }
+        if !max.is_finite() {
+            return Err(BvError::InvalidTable("corrupt max".to_string()));
+        }
+        Ok(table)
+    }
+
+    /// Returns the normalized table as row-major 8-bit grayscale pixels.
+    pub fn to_gray_u8(&self) -> Vec<u8> {
+        self.export()
+            .into_iter()
+            .map(|t| (t.clamp(0.0, 1.0) * 255.0).round() as u8)
+            .collect()
+    }
+
+    /// Renders the table like `to_image` as SVG. Black cells are left out and horizontal runs of
+    /// the same color are merged into a single `<rect>`.
+    pub fn to_svg(&self) -> String {
+        let export = self.export();
+        let size = self.size();
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{size}\" height=\"{size}\" \
+             viewBox=\"0 0 {size} {size}\" shape-rendering=\"crispEdges\" style=\"background:#000\">\n"
+        );
+        for (y, row) in export.chunks_exact(size).enumerate() {
+            let mut x = 0;
+            while x < size {
+                let green = (row[x] * 255.0) as u8;
+                let run = row[x..]
+                    .iter()
+                    .take_while(|&&t| (t * 255.0) as u8 == green)
+                    .count();
+                if green > 0 {
+                    svg.push_str(&format!(
+                        "<rect x=\"{x}\" y=\"{y}\" width=\"{run}\" height=\"1\" fill=\"#00{green:02x}00\"/>\n"
+                    ));
+                }
+                x += run;
+            }
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+
+    pub fn to_image(&self) -> RgbImage {
+        let export = self.export();
+        let size = self.size();
+        RgbImage::from_fn(size as u32, size as u32, |x, y| {
+            let t = export[y as usize * size + x as usize];
+            Rgb([0, (t * 255.0) as u8, 0])
+        })
+    }
+}
+
+impl Default for BinaryTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Records byte triples: every `(first, second)` cell also averages the byte that follows.
+pub struct BinaryTable3 {
+    pub counts: Box<[[u32; 256]; 256]>,
+    pub sums: Box<[[u64; 256]; 256]>,
+}
+
+impl BinaryTable3 {
+    pub fn new() -> Self {
+        Self {
+            counts: Box::new([[0; 256]; 256]),
+            sums: Box::new([[0; 256]; 256]),
+        }
+    }
+
+    pub fn parse(&mut self, bytes: &[u8]) {
+        for window in bytes.windows(3) {
+            let x = window[0] as usize;
+            let y = window[1] as usize;
+            let value = self.counts[y][x].saturating_add(1);
+            self.counts[y][x] = value;
+            self.sums[y][x] += window[2] as u64;
+        }
+    }
+
+    /// The natural logarithm of the largest count, like `BinaryTable::max`.
+    pub fn max(&self) -> f32 {
+        max_ln(self.counts.iter().flatten())
+    }
+
+    /// The average third byte recorded for the `(x, y)` pair, if the pair occurred at all.
+    pub fn third(&self, x: u8, y: u8) -> Option<u8> {
+        let (x, y) = (x as usize, y as usize);
+        match self.counts[y][x] {
+            0 => None,
+            count => Some((self.sums[y][x] / count as u64) as u8),
+        }
+    }
+
+    /// Colors every cell by its average third byte and scales the brightness by the
+    /// log-normalized pair count.
+    pub fn export_rgb(&self) -> Vec<[u8; 3]> {
+        let max = self.max();
+        let mut pixels = vec![[0; 3]; 256 * 256];
+        for y in 0..256 {
+            for x in 0..256 {
+                let Some(third) = self.third(x as u8, y as u8) else {
+                    continue;
+                };
+                let intensity = if max > 0.0 {
+                    (self.counts[y][x] as f32).ln() / max
                 } else {
-                    (value as f32).ln() / self.max
+                    1.0
                 };
-                tensor[y * 256 + x] = t;
+                let color = Colormap::Viridis.color(third as f32 / 255.0);
+                pixels[y * 256 + x] = color.map(|c| (c * intensity * 255.0) as u8);
             }
         }
-        tensor
+        pixels
+    }
+
+    pub fn to_image(&self) -> RgbImage {
+        let pixels = self.export_rgb();
+        RgbImage::from_fn(256, 256, |x, y| Rgb(pixels[y as usize * 256 + x as usize]))
     }
 }
 
-impl Default for BinaryTable {
+impl Default for BinaryTable3 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Counts individual byte values, the distribution the byte-pair table spreads over two axes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ByteHistogram {
+    pub counts: [u64; 256],
+}
+
+impl ByteHistogram {
+    pub fn new() -> Self {
+        Self { counts: [0; 256] }
+    }
+
+    pub fn parse(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.counts[byte as usize] += 1;
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The 256 bar heights between 0 and 1. `Log` uses `ln(1 + count)`, so bytes that occur
+    /// once still get a bar.
+    pub fn export_with(&self, norm: Normalization) -> Vec<f32> {
+        let max = self.counts.iter().copied().max().unwrap_or(0);
+        if max == 0 {
+            return vec![0.0; 256];
+        }
+        self.counts
+            .iter()
+            .map(|&count| match norm {
+                Normalization::Log => (count as f32).ln_1p() / (max as f32).ln_1p(),
+                Normalization::Linear => count as f32 / max as f32,
+                Normalization::None => count as f32,
+            })
+            .collect()
+    }
+}
+
+impl Default for ByteHistogram {
     fn default() -> Self {
         Self::new()
     }
 }
+
+fn max_ln<'a, I>(counts: I) -> f32
+where
+    I: Iterator<Item = &'a u32>,
+{
+    match counts.copied().max().unwrap_or(0) {
+        0 => 0.0,
+        max => (max as f32).ln(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands out at most three bytes per `read`, so pairs span many chunk boundaries.
+    struct ThreeBytes<'a>(&'a [u8]);
+
+    impl Read for ThreeBytes<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = self.0.len().min(buf.len()).min(3);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn parse_reader_carries_pairs_across_chunks() {
+        let bytes: Vec<u8> = (0..1000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut whole = BinaryTable::new();
+        whole.parse(&bytes);
+        let mut streamed = BinaryTable::new();
+        streamed.parse_reader(ThreeBytes(&bytes)).unwrap();
+        assert_eq!(whole.dots, streamed.dots);
+    }
+
+    #[test]
+    fn coarse_resolution_merges_neighbouring_bytes() {
+        let mut table = BinaryTable::with_bits(7).unwrap();
+        table.parse(&[0, 1]);
+        table.parse(&[1, 0]);
+        assert_eq!(table.size(), 128);
+        assert_eq!(table.count_at(0, 0), 2);
+        assert_eq!(table.export().len(), 128 * 128);
+        assert!(BinaryTable::with_bits(9).is_err());
+    }
+
+    #[test]
+    fn export_is_cached_until_the_counts_change() {
+        let computed = |table: &BinaryTable| {
+            table
+                .computed_exports
+                .load(std::sync::atomic::Ordering::Relaxed)
+        };
+        let mut table = BinaryTable::new();
+        table.parse(b"abcabd");
+        let first = table.export();
+        assert_eq!(table.export(), first);
+        assert_eq!(computed(&table), 1);
+        assert_eq!(table.export_shared(Normalization::Log).to_vec(), first);
+        assert_eq!(computed(&table), 1);
+
+        table.export_with(Normalization::Linear);
+        assert_eq!(computed(&table), 2);
+        table.set_count(b'x', b'y', 7);
+        let changed = table.export();
+        assert_ne!(changed, first);
+        assert_eq!(table.export(), changed);
+        assert_eq!(computed(&table), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "different resolutions")]
+    fn diff_rejects_different_resolutions() {
+        BinaryTable::new().diff(&BinaryTable::with_bits(6).unwrap());
+    }
+
+    #[test]
+    fn stride_skips_pairs() {
+        let mut table = BinaryTable::new();
+        table.parse_with_stride(&[1, 2, 3, 4], 2);
+        assert_eq!(table.stats().transitions, 2);
+        assert_eq!(table.count_at(1, 2), 1);
+        assert_eq!(table.count_at(3, 4), 1);
+        table.clear();
+        table.parse_with_stride(&[1, 2, 3, 4], 5);
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn single_byte_exports_zeros() {
+        let mut table = BinaryTable::new();
+        table.parse(&[42]);
+        let export = table.export();
+        assert_eq!(export.len(), 256 * 256);
+        assert!(export.iter().all(|&t| t.is_finite() && t == 0.0));
+    }
+
+    #[test]
+    fn empty_table_renders_black() {
+        let image = BinaryTable::new().to_image();
+        assert_eq!(image.dimensions(), (256, 256));
+        assert!(image.pixels().all(|pixel| pixel.0 == [0, 0, 0]));
+    }
+
+    #[test]
+    fn linear_scales_by_the_largest_count() {
+        let mut table = BinaryTable::new();
+        table.set_count(3, 7, 5);
+        let linear = table.export_with(Normalization::Linear);
+        assert_eq!(linear[7 * 256 + 3], 1.0);
+        assert_eq!(linear.iter().filter(|&&value| value != 0.0).count(), 1);
+        assert_eq!(table.export_with(Normalization::None)[7 * 256 + 3], 5.0);
+    }
+
+    #[test]
+    fn saved_table_round_trips() {
+        let mut table = BinaryTable::new();
+        table.parse(b"round trip through the binary table format");
+        let mut bytes = Vec::new();
+        table.write_to(&mut bytes).unwrap();
+        let loaded = BinaryTable::read_from(&bytes[..]).unwrap();
+        assert_eq!(loaded.export(), table.export());
+
+        let coarse = BinaryTable::with_bits(4).unwrap();
+        let mut bytes = Vec::new();
+        coarse.write_to(&mut bytes).unwrap();
+        assert_eq!(BinaryTable::read_from(&bytes[..]).unwrap().bits(), 4);
+    }
+
+    #[test]
+    fn truncated_or_corrupt_table_is_an_error() {
+        let mut bytes = Vec::new();
+        BinaryTable::new().write_to(&mut bytes).unwrap();
+        let truncated = &bytes[..bytes.len() - 1];
+        assert!(matches!(
+            BinaryTable::read_from(truncated),
+            Err(BvError::InvalidTable(_))
+        ));
+        let mut corrupt = bytes.clone();
+        corrupt[0] ^= 0xff;
+        assert!(matches!(
+            BinaryTable::read_from(&corrupt[..]),
+            Err(BvError::InvalidTable(_))
+        ));
+        assert!(BinaryTable::read_from(&[][..]).is_err());
+    }
+
+    #[test]
+    fn entropy_of_uniform_and_peaked_tables() {
+        assert_eq!(BinaryTable::new().stats().entropy, 0.0);
+
+        let mut uniform = BinaryTable::new();
+        for x in 0..4 {
+            uniform.set_count(x, 0, 10);
+        }
+        let stats = uniform.stats();
+        assert_eq!(stats.transitions, 40);
+        assert_eq!(stats.occupied, 4);
+        assert!((stats.entropy - 2.0).abs() < 1e-6);
+
+        let mut peaked = BinaryTable::new();
+        peaked.set_count(0, 0, 37);
+        for x in 1..4 {
+            peaked.set_count(x, 0, 1);
+        }
+        let stats = peaked.stats();
+        assert!(stats.entropy < 1.0);
+        assert_eq!(stats.most_frequent, Some(((0, 0), 37)));
+    }
+
+    #[test]
+    fn downsampling_keeps_a_hot_cell_in_its_block() {
+        let mut table = BinaryTable::new();
+        table.set_count(5, 9, 3);
+        let coarse = table.downsample(2).unwrap();
+        assert_eq!(coarse.len(), 128 * 128);
+        assert_eq!(coarse[4 * 128 + 2], 0.25);
+        assert_eq!(coarse.iter().filter(|&&value| value != 0.0).count(), 1);
+        assert!(table.downsample(3).is_err());
+        assert!(table.downsample(0).is_err());
+    }
+
+    #[test]
+    fn merging_single_dot_tables_adds_counts() {
+        let single_dot = |count| {
+            let mut table = BinaryTable::new();
+            table.set_count(b'a', b'b', count);
+            table
+        };
+        let mut merged = single_dot(1);
+        merged.merge(&single_dot(1));
+        assert_eq!(merged.count_at(b'a', b'b'), 2);
+        assert_eq!(merged.max(), 2f32.ln());
+
+        let mut saturated = single_dot(u32::MAX);
+        saturated.merge(&single_dot(1));
+        assert_eq!(saturated.count_at(b'a', b'b'), u32::MAX);
+    }
+
+    #[test]
+    fn diff_marks_the_single_differing_cell() {
+        let mut a = BinaryTable::new();
+        a.set_count(1, 1, 3);
+        a.set_count(2, 2, 3);
+        let mut b = BinaryTable::new();
+        b.set_count(1, 1, 3);
+        let diff = a.diff(&b);
+        assert_eq!(diff[2 * 256 + 2], 1.0);
+        assert_eq!(diff.iter().filter(|&&value| value != 0.0).count(), 1);
+        assert_eq!(b.diff(&a)[2 * 256 + 2], -1.0);
+    }
+
+    #[test]
+    fn largest_count_maps_to_white() {
+        assert!(BinaryTable::new()
+            .to_gray_u8()
+            .iter()
+            .all(|&value| value == 0));
+        let mut table = BinaryTable::new();
+        table.set_count(10, 20, 50);
+        table.set_count(30, 40, 7);
+        let gray = table.to_gray_u8();
+        assert_eq!(gray.len(), 256 * 256);
+        assert_eq!(gray[20 * 256 + 10], 255);
+        assert!(gray[40 * 256 + 30] < 255);
+    }
+
+    #[test]
+    fn third_byte_colors_the_pair() {
+        let mut table = BinaryTable3::new();
+        table.parse(&[10, 20, 200]);
+        assert_eq!(table.third(10, 20), Some(200));
+        assert_eq!(table.third(20, 200), None);
+        let expected = Colormap::Viridis
+            .color(200.0 / 255.0)
+            .map(|c| (c * 255.0) as u8);
+        assert_eq!(table.export_rgb()[20 * 256 + 10], expected);
+    }
+
+    #[test]
+    fn clipping_brightens_mid_range_cells() {
+        let mut table = BinaryTable::new();
+        for (x, count) in [10, 20, 30, 1000, 100_000].into_iter().enumerate() {
+            table.set_count(x as u8, 0, count);
+        }
+        let plain = table.export();
+        let clipped = table.export_clipped(50.0);
+        assert!(clipped[1] > plain[1], "{} <= {}", clipped[1], plain[1]);
+        assert_eq!(clipped[2], 1.0);
+        assert_eq!(clipped[4], 1.0);
+    }
+
+    #[test]
+    fn masked_zero_pair_no_longer_sets_the_scale() {
+        let mut table = BinaryTable::new();
+        table.set_count(0, 0, 1000);
+        table.set_count(1, 0, 4);
+        table.set_count(2, 0, 2);
+        assert!(table.export()[1] < 1.0);
+        let export = table.export_masked(Normalization::Log, true);
+        assert_eq!(export[0], 0.0);
+        assert_eq!(export[1], 1.0);
+        assert_eq!(export[2], 0.5);
+        let linear = table.export_masked(Normalization::Linear, true);
+        assert_eq!(&linear[..3], [0.0, 1.0, 0.5]);
+        assert_eq!(table.count_at(0, 0), 1000);
+        assert_eq!(table.export()[0], 1.0);
+
+        let copy = table.without_zero_pair();
+        assert_eq!(copy.count_at(0, 0), 0);
+        assert_eq!(copy.export(), export);
+    }
+
+    #[test]
+    fn empty_table_svg_has_no_rects() {
+        let svg = BinaryTable::new().to_svg();
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\""));
+        assert!(svg.trim_end().ends_with("</svg>"));
+        assert!(!svg.contains("<rect"));
+
+        let mut table = BinaryTable::new();
+        table.parse(b"abab");
+        assert!(table.to_svg().contains("<rect"));
+    }
+
+    #[test]
+    fn lazy_max_exports_like_the_eager_max() {
+        let bytes: Vec<u8> = (0..5000u32).map(|i| (i * i % 251) as u8).collect();
+        let mut table = BinaryTable::new();
+        table.parse(&bytes[..2000]);
+        table.parse(&bytes[2000..]);
+
+        // The counts and max as `parse` tracked them before the max was derived on export.
+        let mut counts = vec![0u32; 256 * 256];
+        let mut max = 0f32;
+        for chunk in [&bytes[..2000], &bytes[2000..]] {
+            for pair in chunk.windows(2) {
+                let cell = &mut counts[pair[1] as usize * 256 + pair[0] as usize];
+                *cell += 1;
+                max = max.max((*cell as f32).ln());
+            }
+        }
+        let expected: Vec<f32> = counts
+            .iter()
+            .map(|&count| {
+                if count == 0 {
+                    0.0
+                } else {
+                    (count as f32).ln() / max
+                }
+            })
+            .collect();
+        assert_eq!(table.max(), max);
+        assert_eq!(table.export(), expected);
+    }
+
+    #[test]
+    fn from_counts_exports_like_parse() {
+        let bytes = b"counts built by hand should export like parsed ones";
+        let mut parsed = BinaryTable::new();
+        parsed.parse(bytes);
+        let mut counts = [[0; 256]; 256];
+        for pair in bytes.windows(2) {
+            counts[pair[1] as usize][pair[0] as usize] += 1;
+        }
+        let built = BinaryTable::from_counts(counts);
+        assert_eq!(built.export(), parsed.export());
+        assert_eq!(built.count_at(b'o', b'u'), parsed.count_at(b'o', b'u'));
+        assert_eq!(built.count_at(b'o', b'u'), 2);
+    }
+
+    #[test]
+    fn csv_dump_has_a_row_of_counts_per_second_byte() {
+        let bytes = b"a tiny file";
+        let mut table = BinaryTable::new();
+        table.parse(bytes);
+        let mut csv = Vec::new();
+        table.write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 256);
+        let mut total = 0;
+        for line in lines {
+            let counts: Vec<u64> = line
+                .split(',')
+                .map(|count| count.parse().unwrap())
+                .collect();
+            assert_eq!(counts.len(), 256);
+            total += counts.iter().sum::<u64>();
+        }
+        assert_eq!(total, bytes.len() as u64 - 1);
+    }
+
+    #[test]
+    fn equalization_spreads_skewed_counts() {
+        let mut table = BinaryTable::new();
+        for (x, count) in [2, 3, 4, 5, 6, 7, 1_000_000].into_iter().enumerate() {
+            table.set_count(x as u8, 0, count);
+        }
+        // The mean distance of the sorted nonzero values from evenly spaced ones.
+        let unevenness = |export: Vec<f32>| {
+            let mut values: Vec<f32> = export.into_iter().filter(|&t| t > 0.0).collect();
+            values.sort_by(f32::total_cmp);
+            let n = values.len() as f32;
+            let distance: f32 = values
+                .iter()
+                .enumerate()
+                .map(|(i, t)| (t - (i + 1) as f32 / n).abs())
+                .sum();
+            distance / n
+        };
+        let equalized = unevenness(table.export_equalized());
+        let log = unevenness(table.export());
+        assert!(equalized < log, "{equalized} >= {log}");
+    }
+
+    #[test]
+    fn identical_tables_are_at_distance_zero() {
+        let table = |bytes: &[u8]| {
+            let mut table = BinaryTable::new();
+            table.parse(bytes);
+            table
+        };
+        let a = table(b"the same bytes, the same bytes");
+        let b = table(b"the same bytes, the same bytes");
+        assert!(a.distance(&b, Metric::Cosine).abs() < 1e-6);
+        assert_eq!(a.distance(&b, Metric::L2), 0.0);
+        assert!(a.distance(&table(b"\x00\x01\x02\x03"), Metric::Cosine) > 0.0);
+
+        let empty = BinaryTable::new();
+        assert_eq!(empty.distance(&BinaryTable::new(), Metric::Cosine), 0.0);
+        assert_eq!(empty.distance(&a, Metric::Cosine), 1.0);
+        assert!(empty.distance(&a, Metric::L2).is_finite());
+    }
+
+    #[test]
+    fn byte_histogram_counts_every_byte() {
+        let mut histogram = ByteHistogram::new();
+        histogram.parse(b"aab\x00");
+        histogram.parse(b"b");
+        assert_eq!(histogram.total(), 5);
+        assert_eq!(histogram.counts[b'a' as usize], 2);
+        assert_eq!(histogram.counts[b'b' as usize], 2);
+        assert_eq!(histogram.counts[0], 1);
+        assert_eq!(
+            histogram.counts.iter().filter(|&&count| count > 0).count(),
+            3
+        );
+
+        let linear = histogram.export_with(Normalization::Linear);
+        assert_eq!(
+            (linear[b'a' as usize], linear[0], linear[255]),
+            (1.0, 0.5, 0.0)
+        );
+        assert!(histogram.export_with(Normalization::Log)[0] > 0.0);
+        assert_eq!(
+            ByteHistogram::new().export_with(Normalization::Log),
+            [0.0; 256]
+        );
+    }
+
+    #[test]
+    fn row_normalization_lifts_every_nonempty_row_to_one() {
+        let mut table = BinaryTable::new();
+        table.parse(b"xxxxxxxxyxzxyyzx\x00\x01\x00\x01");
+        let global = table.export();
+        let rows = table.export_normalized(Axis::Row);
+        assert_eq!(global, table.export_normalized(Axis::Global));
+        for y in 0..256 {
+            let row = &rows[y * 256..(y + 1) * 256];
+            let max = row.iter().copied().fold(0.0, f32::max);
+            let nonempty = (0..=255).any(|x| table.count_at(x, y as u8) > 0);
+            assert_eq!(max, if nonempty { 1.0 } else { 0.0 }, "row {y}");
+        }
+        let y = b'y' as usize;
+        assert!(global[y * 256..(y + 1) * 256]
+            .iter()
+            .all(|&value| value < 1.0));
+    }
+}